@@ -0,0 +1,43 @@
+use std::time::Duration;
+
+use illumos_audio::{sys::AudioFormats, Direction, Dsp, Stream, StreamConfig};
+
+pub fn main() -> std::io::Result<()> {
+    let path = std::env::args().nth(1).unwrap_or_else(|| "/dev/dsp".into());
+    let dsp = Dsp::open(&path, Direction::Duplex)?;
+
+    let config = StreamConfig {
+        format: AudioFormats::AFMT_S16_LE,
+        rate: 48000,
+        channels: 2,
+    };
+
+    /*
+     * Pass the input straight through to the output at half volume.
+     */
+    let stream = Stream::duplex_with_error_callback(
+        dsp,
+        config,
+        |input, output| {
+            for (i, o) in input.chunks_exact(2).zip(output.chunks_exact_mut(2))
+            {
+                let v = i16::from_le_bytes([i[0], i[1]]) / 2;
+                o.copy_from_slice(&v.to_le_bytes());
+            }
+        },
+        |e| eprintln!("stream error: {e:?}"),
+    )?;
+
+    let offset = stream.io_offset_frames().unwrap();
+    println!(
+        "passing input through to output for 5 seconds ({offset} frames, \
+        {:.1} ms of buffering)...",
+        offset as f64 * 1000.0 / config.rate as f64,
+    );
+    std::thread::sleep(Duration::from_secs(5));
+
+    stream.stop()?;
+    println!("ok!");
+
+    Ok(())
+}
@@ -1,4 +1,21 @@
-use illumos_audio::Mixer;
+use illumos_audio::{mixer::SystemSnapshot, Mixer};
+
+#[cfg(feature = "serde")]
+fn print_json(snap: &SystemSnapshot) -> std::io::Result<()> {
+    serde_json::to_writer_pretty(std::io::stdout().lock(), snap)?;
+    println!();
+    Ok(())
+}
+
+#[cfg(not(feature = "serde"))]
+fn print_json(_: &SystemSnapshot) -> std::io::Result<()> {
+    eprintln!("JSON output requires the \"serde\" feature");
+    std::process::exit(1);
+}
+
+fn indent(s: String) -> String {
+    s.lines().map(|l| format!("    {l}\n")).collect()
+}
 
 pub fn main() -> std::io::Result<()> {
     let mixer = Mixer::open()?;
@@ -6,31 +23,35 @@ pub fn main() -> std::io::Result<()> {
     let (maj, min) = mixer.version();
     println!("OSS version = {maj}.{min}");
 
-    let si = mixer.sysinfo()?;
-    println!("sysinfo = {si:#?}");
+    let snap = mixer.snapshot()?;
+
+    if std::env::args().skip(1).any(|a| a == "--json") {
+        return print_json(&snap);
+    }
+
+    println!("sysinfo = {:#?}", snap.sysinfo);
 
     println!("AUDIO INFO:");
-    for i in 0..si.num_audios {
-        let info = mixer.audioinfo(i)?;
-        let out = format!("audioinfo[{i}] = {info:#?}").lines()
-            .map(|l| format!("    {l}\n")).collect::<String>();
-        println!("{out}");
+    for (i, info) in snap.audio_devices.iter().enumerate() {
+        println!("{}", indent(format!("audioinfo[{i}] = {info:#?}")));
+
+        if let Some(card) = snap.card(info) {
+            println!("        card = {}", card.shortname);
+        }
+        if let Some(mixer) = snap.mixer(info) {
+            println!("        mixer = {} ({})", mixer.name, mixer.devnode);
+        }
+        println!();
     }
 
     println!("CARD INFO:");
-    for i in 0..si.num_cards {
-        let info = mixer.cardinfo(i)?;
-        let out = format!("cardinfo[{i}] = {info:#?}").lines()
-            .map(|l| format!("    {l}\n")).collect::<String>();
-        println!("{out}");
+    for (i, info) in snap.cards.iter().enumerate() {
+        println!("{}", indent(format!("cardinfo[{i}] = {info:#?}")));
     }
 
     println!("MIXER INFO:");
-    for i in 0..si.num_mixers {
-        let info = mixer.mixerinfo(i)?;
-        let out = format!("mixerinfo[{i}] = {info:#?}").lines()
-            .map(|l| format!("    {l}\n")).collect::<String>();
-        println!("{out}");
+    for (i, info) in snap.mixers.iter().enumerate() {
+        println!("{}", indent(format!("mixerinfo[{i}] = {info:#?}")));
     }
 
     Ok(())
@@ -0,0 +1,109 @@
+use std::time::Duration;
+
+use illumos_audio::{
+    latency::{self, LatencyConfig, Stimulus},
+    Direction, Dsp, Mixer,
+};
+
+fn usage() -> ! {
+    eprintln!("usage: latency [-c] [-n RUNS] [-i INPUT] [OUTPUT]");
+    eprintln!();
+    eprintln!("    -c          use a chirp rather than an MLS stimulus");
+    eprintln!("    -n RUNS     number of measurements to make (default 5)");
+    eprintln!("    -i INPUT    record on a different device");
+    eprintln!();
+    eprintln!("Devices may be given as a path, or as an audio device number");
+    eprintln!("from the mixer (see the \"enumerate\" example).");
+    std::process::exit(2);
+}
+
+/**
+ * Resolve an audio device number to its device node, or pass a path through
+ * unchanged.
+ */
+fn devnode(arg: &str) -> std::io::Result<String> {
+    match arg.parse::<u32>() {
+        Ok(n) => Ok(Mixer::open()?.audioinfo(n)?.devnode),
+        Err(_) => Ok(arg.to_string()),
+    }
+}
+
+pub fn main() -> std::io::Result<()> {
+    let mut config = LatencyConfig::default();
+    let mut input = None;
+    let mut output = None;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(a) = args.next() {
+        match a.as_str() {
+            "-c" => {
+                config.stimulus = Stimulus::Chirp {
+                    duration: Duration::from_millis(250),
+                    f0: 50.0,
+                    f1: 16000.0,
+                };
+            }
+            "-n" => {
+                let Some(n) = args.next().and_then(|n| n.parse().ok()) else {
+                    usage();
+                };
+                config.runs = n;
+            }
+            "-i" => {
+                let Some(i) = args.next() else {
+                    usage();
+                };
+                input = Some(devnode(&i)?);
+            }
+            o if !o.starts_with('-') && output.is_none() => {
+                output = Some(devnode(o)?);
+            }
+            _ => usage(),
+        }
+    }
+    let output = output.unwrap_or_else(|| "/dev/dsp".into());
+
+    let report = if let Some(input) = &input {
+        println!("measuring {output} -> {input}...");
+        let out = Dsp::open(&output, Direction::Play)?;
+        let inp = Dsp::open(input, Direction::Record)?;
+        latency::measure(&out, Some(&inp), &config)?
+    } else {
+        println!("measuring {output} (duplex)...");
+        let dsp = Dsp::open(&output, Direction::Duplex)?;
+        latency::measure(&dsp, None, &config)?
+    };
+
+    for (i, run) in report.runs.iter().enumerate() {
+        match run.frames {
+            Some(f) => println!(
+                "    run {i}: {f} frames ({:.2} ms), confidence {:.1}",
+                f as f64 * 1000.0 / report.rate as f64,
+                run.confidence,
+            ),
+            None => println!(
+                "    run {i}: not detected, confidence {:.1}",
+                run.confidence,
+            ),
+        }
+    }
+
+    let (Some(mean), Some(jitter), Some(min), Some(max)) = (
+        report.mean_ms(),
+        report.jitter_ms(),
+        report.min_ms(),
+        report.max_ms(),
+    ) else {
+        println!("no stimulus detected; is there a loopback connection?");
+        std::process::exit(1);
+    };
+
+    println!(
+        "round trip latency: mean {mean:.2} ms, jitter {jitter:.2} ms \
+        (min {min:.2} ms, max {max:.2} ms, {}/{} runs)",
+        report.detections(),
+        report.runs.len(),
+    );
+
+    Ok(())
+}
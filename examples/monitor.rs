@@ -0,0 +1,49 @@
+use std::time::Duration;
+
+use illumos_audio::{DeviceEvent, DeviceMonitor, Mixer};
+
+pub fn main() -> std::io::Result<()> {
+    let monitor = DeviceMonitor::new(Mixer::open()?)?;
+    for info in monitor.devices() {
+        println!("present: {} ({})", info.name, info.devnode);
+    }
+
+    let (_handle, events) = monitor.channel(Duration::from_secs(1))?;
+    println!("watching for changes...");
+
+    for ev in events {
+        match ev {
+            DeviceEvent::Added(info) => {
+                println!("added: {} ({})", info.name, info.devnode);
+            }
+            DeviceEvent::Removed(info) => {
+                println!("removed: {} ({})", info.name, info.devnode);
+            }
+            DeviceEvent::Changed { old, new } => {
+                println!(
+                    "changed: {} ({}): busy {:?} -> {:?}, enabled {} -> {}",
+                    new.name,
+                    new.devnode,
+                    old.busy,
+                    new.busy,
+                    old.enabled,
+                    new.enabled,
+                );
+            }
+            DeviceEvent::CardAdded(card) => {
+                println!("card added: {}", card.longname);
+            }
+            DeviceEvent::CardRemoved(card) => {
+                println!("card removed: {}", card.longname);
+            }
+            DeviceEvent::MixerAdded(mixer) => {
+                println!("mixer added: {} ({})", mixer.name, mixer.devnode);
+            }
+            DeviceEvent::MixerRemoved(mixer) => {
+                println!("mixer removed: {} ({})", mixer.name, mixer.devnode);
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -1,4 +1,7 @@
-use illumos_audio::{Dsp, Mixer};
+use illumos_audio::{
+    generator::{Generator, Oscillator, Waveform},
+    Dsp, Mixer, SampleFormat,
+};
 
 pub fn main() -> std::io::Result<()> {
     let mixer = Mixer::open()?;
@@ -18,7 +21,7 @@ pub fn main() -> std::io::Result<()> {
             .collect::<String>();
         println!("{out}");
 
-        let mut dsp = Dsp::open_path(&info.devnode)?;
+        let dsp = Dsp::open_path(&info.devnode)?;
 
         println!("    syncing...");
         dsp.sync()?;
@@ -67,34 +70,18 @@ pub fn main() -> std::io::Result<()> {
         println!();
 
         /*
-         * Generate a tone, I guess?
+         * Play a second of middle C as a quiet square wave, 10ms at a time.
          */
-        let samps_per = speed / 261;
-        let mut on = true;
-        let mut nsamps = 0;
-
-        /*
-         * Audio buffer with enough space for 10ms of audio...
-         */
-        let mut buf = Vec::with_capacity((speed * 4 / 100).try_into().unwrap());
+        let mut tone = Generator::new(
+            Oscillator::new(Waveform::Square, 261.63, speed),
+            SampleFormat::S24LE,
+            1,
+        )
+        .level_db(-40.0);
+
+        let mut buf = vec![0u8; (speed as usize / 100) * 4];
         for _ in 0..100 {
-            buf.clear();
-            for i in 0..(speed / 100) {
-                nsamps += 1;
-                if nsamps > samps_per {
-                    nsamps = 0;
-                    on = !on;
-                }
-
-                let v =
-                    if on { 1000i32 } else { -1000i32 }.to_le_bytes();
-
-                buf.push(0);
-                buf.push(v[0]);
-                buf.push(v[1]);
-                buf.push(v[2]);
-            }
-
+            tone.fill(&mut buf);
             dsp.play(&buf)?;
         }
 
@@ -0,0 +1,58 @@
+use std::time::Duration;
+
+use illumos_audio::{sys::AudioFormats, Direction, Dsp, Stream, StreamConfig};
+
+pub fn main() -> std::io::Result<()> {
+    let dsp = match std::env::args().nth(1) {
+        Some(path) => Dsp::open_path(path)?,
+        None => {
+            let (dsp, info) = Dsp::open_default(Direction::Play)?;
+            println!("using default device {} ({})", info.name, info.devnode);
+            dsp
+        }
+    };
+
+    let config = StreamConfig {
+        format: AudioFormats::AFMT_S16_LE,
+        rate: 48000,
+        channels: 2,
+    };
+
+    /*
+     * Generate a middle C square wave.  The stream asks for exactly as many
+     * frames as the device can accept, so we need only keep track of where
+     * we are in the cycle.
+     */
+    let half_period = config.rate / 261 / 2;
+    let mut n = 0;
+    let stream = Stream::with_error_callback(
+        dsp,
+        config,
+        move |buf| {
+            for frame in buf.chunks_exact_mut(4) {
+                let v = if n < half_period { 1000i16 } else { -1000i16 };
+                n = (n + 1) % (half_period * 2);
+
+                frame[0..2].copy_from_slice(&v.to_le_bytes());
+                frame[2..4].copy_from_slice(&v.to_le_bytes());
+            }
+        },
+        |e| eprintln!("stream error: {e:?}"),
+    )?;
+
+    println!("playing...");
+    std::thread::sleep(Duration::from_secs(1));
+
+    println!("pausing...");
+    stream.pause();
+    std::thread::sleep(Duration::from_millis(500));
+
+    println!("resuming...");
+    stream.resume();
+    std::thread::sleep(Duration::from_secs(1));
+
+    stream.stop()?;
+    println!("ok!");
+
+    Ok(())
+}
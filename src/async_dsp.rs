@@ -0,0 +1,142 @@
+use std::{
+    pin::Pin,
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use tokio::io::{unix::AsyncFd, AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::Dsp;
+
+/*
+ * Bounds on how long we sleep between checks of the play queue depth while
+ * draining.
+ */
+const SYNC_POLL_MIN: Duration = Duration::from_millis(1);
+const SYNC_POLL_MAX: Duration = Duration::from_millis(50);
+
+/**
+ * A DSP device driven by the tokio runtime.  Audio is played by writing to
+ * the object with AsyncWrite, and recorded by reading from it with AsyncRead,
+ * without tying up a runtime worker thread while the device buffer is full (or
+ * empty).  The wrapped Dsp remains available for configuration ioctls through
+ * get_ref().
+ */
+#[derive(Debug)]
+pub struct AsyncDsp {
+    inner: AsyncFd<Dsp>,
+}
+
+impl AsyncDsp {
+    /**
+     * Register an open DSP device with the current tokio runtime.  The file
+     * descriptor is switched to non-blocking mode.  This must be called from
+     * within a runtime context.
+     */
+    pub fn new(dsp: Dsp) -> std::io::Result<Self> {
+        dsp.nonblocking_set(true)?;
+
+        /*
+         * The Dsp owns its file descriptor, which therefore stays open and
+         * unchanged until the AsyncFd is dropped or into_inner() returns it.
+         */
+        let inner = unsafe { AsyncFd::register(dsp)? };
+        Ok(AsyncDsp { inner })
+    }
+
+    pub fn get_ref(&self) -> &Dsp {
+        self.inner.get_ref()
+    }
+
+    /**
+     * Deregister from the runtime and return the device, which is left in
+     * non-blocking mode.
+     */
+    pub fn into_inner(self) -> Dsp {
+        self.inner.into_inner()
+    }
+
+    /**
+     * Wait until all queued audio has been played.  Unlike Dsp::sync(), this
+     * does not issue SNDCTL_DSP_SYNC (which would block the calling thread);
+     * instead, we watch the queue depth reported by delay() and sleep in
+     * proportion to the amount of audio that remains.
+     */
+    pub async fn sync(&self) -> std::io::Result<()> {
+        let dsp = self.get_ref();
+
+        let ss = dsp
+            .format()?
+            .sample_size()
+            .ok_or_else(|| std::io::Error::from_raw_os_error(libc::EINVAL))?;
+        let bps = (dsp.speed()? as u64)
+            .saturating_mul(dsp.channels()? as u64)
+            .saturating_mul(ss as u64)
+            .max(1);
+
+        loop {
+            let queued = dsp.delay()? as u64;
+            if queued == 0 {
+                return Ok(());
+            }
+
+            let remaining =
+                Duration::from_micros(queued.saturating_mul(1_000_000) / bps);
+            tokio::time::sleep(remaining.clamp(SYNC_POLL_MIN, SYNC_POLL_MAX))
+                .await;
+        }
+    }
+}
+
+impl AsyncWrite for AsyncDsp {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        loop {
+            let mut guard = ready!(self.inner.poll_write_ready(cx))?;
+
+            match guard.try_io(|inner| inner.get_ref().play_some(buf)) {
+                Ok(res) => return Poll::Ready(res),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        /*
+         * Writes are passed straight to the device, so there is nothing for
+         * us to flush.  Use sync() to wait for queued audio to be played.
+         */
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncRead for AsyncDsp {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        loop {
+            let mut guard = ready!(self.inner.poll_read_ready(cx))?;
+
+            let unfilled = buf.initialize_unfilled();
+            match guard.try_io(|inner| inner.get_ref().record(unfilled)) {
+                Ok(res) => return Poll::Ready(res.map(|n| buf.advance(n))),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
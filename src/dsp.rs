@@ -1,27 +1,79 @@
-use std::{fs::File, os::fd::AsRawFd, path::Path};
+use std::{
+    fs::File,
+    os::fd::{AsFd, AsRawFd, BorrowedFd, RawFd},
+    path::Path,
+    time::Duration,
+};
 
 use libc::c_void;
 
-use crate::{basic_ioctl, basic_ioctl_inout, basic_ioctl_noarg, sys};
+use crate::{
+    basic_ioctl, basic_ioctl_inout, basic_ioctl_noarg, c_chars_to_string,
+    c_int_to_u32,
+    gain::{Gain, Ramp},
+    mixer::AudioInfo,
+    poll_one,
+    sample::SampleFormat,
+    sys, u32_to_c_int,
+    volume::Volume,
+    Mixer,
+};
+
+/*
+ * If set, this environment variable names the device to use in place of the
+ * system default, either as a path or as an audio device number.
+ */
+const AUDIODEV_ENV: &str = "AUDIODEV";
+
+const DEFAULT_DSP: &str = "/dev/dsp";
 
 /**
  * DSP device nodes are how we play or record audio to specific outputs on the
  * system.
+ *
+ * The underlying file descriptor is available through AsFd and AsRawFd so
+ * that a Dsp can be registered with an external event loop.  The device
+ * reports POLLOUT when at least the low water mark worth of bytes may be
+ * written to the play buffer without blocking, and POLLIN when at least the
+ * low water mark worth of recorded bytes may be read.  Unless changed with
+ * low_water_set(), the low water mark is one fragment; see the "fragsize"
+ * member returned by space_output() and space_input().  Readiness is level
+ * triggered: it remains asserted until enough data has been written (or read)
+ * to cross the mark again.
  */
 #[derive(Debug)]
 pub struct Dsp {
     f: File,
 }
 
+/**
+ * Whether a DSP device is opened to play audio, record audio, or both at once.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Play,
+    Record,
+    Duplex,
+}
+
 impl Dsp {
+    /**
+     * Open a DSP device for playback.
+     */
     pub fn open_path<P: AsRef<Path>>(dsp: P) -> std::io::Result<Self> {
+        Self::open(dsp, Direction::Play)
+    }
+
+    pub fn open<P: AsRef<Path>>(
+        dsp: P,
+        direction: Direction,
+    ) -> std::io::Result<Self> {
         let p = dsp.as_ref();
 
-        /*
-         * XXX the open mode seems like it needs to reflect whether we are
-         * expecting to record (read) or play (write) or both...
-         */
-        let f = std::fs::OpenOptions::new().write(true).open(p)?;
+        let f = std::fs::OpenOptions::new()
+            .read(direction != Direction::Play)
+            .write(direction != Direction::Record)
+            .open(p)?;
 
         /*
          * Perform an initial ioctl to get the OSS API version.
@@ -44,6 +96,49 @@ impl Dsp {
         Ok(Dsp { f })
     }
 
+    /**
+     * Open the system default device for the given direction, returning the
+     * device along with the description of the device that was chosen.
+     *
+     * If the AUDIODEV environment variable is set, it names the device to
+     * use, either as a path or as an audio device number from the mixer.
+     * Otherwise, we use the device that the mixer marks with PCM_CAP_DEFAULT
+     * and that supports the requested direction.  If there is no such device,
+     * we fall back to "/dev/dsp".
+     */
+    pub fn open_default(
+        direction: Direction,
+    ) -> std::io::Result<(Self, AudioInfo)> {
+        let path = match AudioDev::from_env() {
+            Some(dev) => dev.dsp_path()?,
+            None => Mixer::open()
+                .ok()
+                .and_then(|m| default_devnode(&m, direction))
+                .unwrap_or_else(|| DEFAULT_DSP.to_string()),
+        };
+
+        let dsp = Self::open(path, direction)?;
+        let info = dsp.audioinfo()?;
+        Ok((dsp, info))
+    }
+
+    /**
+     * Describe the device this handle refers to.
+     */
+    pub fn audioinfo(&self) -> std::io::Result<AudioInfo> {
+        /*
+         * A device number of -1 asks about the device on which the ioctl is
+         * issued, rather than about some other device in the system.
+         */
+        let buf: sys::oss_audioinfo = basic_ioctl_inout(
+            &self.f,
+            sys::SNDCTL_AUDIOINFO,
+            sys::oss_audioinfo { dev: -1, ..Default::default() },
+        )?;
+
+        AudioInfo::from_raw(&buf)
+    }
+
     pub fn sync(&self) -> std::io::Result<()> {
         basic_ioctl_noarg(&self.f, sys::SNDCTL_DSP_SYNC)
     }
@@ -60,23 +155,131 @@ impl Dsp {
         basic_ioctl_noarg(&self.f, sys::SNDCTL_DSP_HALT_OUTPUT)
     }
 
+    /**
+     * Stop playback without a pop.  Rather than cutting the output off
+     * mid-waveform as halt_output() does, we queue a tail that starts from
+     * "last_frame" (the final frame the caller wrote) and fades linearly to
+     * silence over "fade", wait for everything queued to be played, and only
+     * then halt.
+     *
+     * Audio that was queued before the call is played in full, so this
+     * returns after roughly the queued time (as reported by delay()) plus
+     * the fade; that total is returned.  The last frame must be exactly one
+     * frame in the configured format, which must be one SampleFormat knows.
+     */
+    pub fn fade_out(
+        &self,
+        last_frame: &[u8],
+        fade: Duration,
+    ) -> std::io::Result<Duration> {
+        let einval = || std::io::Error::from_raw_os_error(libc::EINVAL);
+
+        let format = SampleFormat::from_audio_format(self.format()?)
+            .ok_or_else(einval)?;
+        let channels = self.channels()? as usize;
+        let rate = self.speed()?;
+        let frame_size = format.size() * channels;
+        if last_frame.len() != frame_size {
+            return Err(einval());
+        }
+
+        let frames = (fade.as_secs_f64() * f64::from(rate)).round() as usize;
+        let mut tail = last_frame.repeat(frames);
+        let mut gain = Gain::new(format, channels, rate);
+        gain.mute(Ramp::Linear(fade));
+        gain.process(&mut tail);
+
+        let queued = self.delay()? / frame_size;
+        let total =
+            Duration::from_secs_f64((queued + frames) as f64 / f64::from(rate));
+
+        let mut rest = &tail[..];
+        while !rest.is_empty() {
+            match self.play_some(rest) {
+                Ok(n) => rest = &rest[n..],
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    self.wait_writable(None)?;
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+
+        self.sync()?;
+        self.halt_output()?;
+        Ok(total)
+    }
+
+    /**
+     * Put the device in full duplex mode, so that it may record and play at
+     * the same time.  The device must have been opened with
+     * Direction::Duplex.
+     */
+    pub fn duplex_set(&self) -> std::io::Result<()> {
+        basic_ioctl_noarg(&self.f, sys::SNDCTL_DSP_SETDUPLEX)
+    }
+
     pub fn errors(&self) -> std::io::Result<ErrorInfo> {
         let ei: sys::audio_errinfo =
             basic_ioctl(&self.f, sys::SNDCTL_DSP_GETERROR)?;
         Ok(ErrorInfo {
-            play_underruns: ei.play_underruns.try_into().unwrap(),
-            rec_overruns: ei.rec_overruns.try_into().unwrap(),
+            play_underruns: c_int_to_u32(ei.play_underruns)?,
+            rec_overruns: c_int_to_u32(ei.rec_overruns)?,
         })
     }
 
-    pub fn volume_play(&self) -> std::io::Result<u8> {
-        let v: libc::c_int = basic_ioctl(&self.f, sys::SNDCTL_DSP_GETPLAYVOL)?;
-        Ok((v & 0xFF).try_into().unwrap())
+    pub fn volume_play(&self) -> std::io::Result<Volume> {
+        Volume::from_raw(basic_ioctl(&self.f, sys::SNDCTL_DSP_GETPLAYVOL)?)
+    }
+
+    pub fn volume_play_set(&self, volume: Volume) -> std::io::Result<()> {
+        basic_ioctl_inout(&self.f, sys::SNDCTL_DSP_SETPLAYVOL, volume.raw())?;
+        Ok(())
+    }
+
+    pub fn volume_record(&self) -> std::io::Result<Volume> {
+        Volume::from_raw(basic_ioctl(&self.f, sys::SNDCTL_DSP_GETRECVOL)?)
+    }
+
+    pub fn volume_record_set(&self, volume: Volume) -> std::io::Result<()> {
+        basic_ioctl_inout(&self.f, sys::SNDCTL_DSP_SETRECVOL, volume.raw())?;
+        Ok(())
+    }
+
+    /**
+     * The names of the inputs this device can record from (e.g., "mic" or
+     * "line"), in order; a source is selected by its index in this list.
+     */
+    pub fn record_sources(&self) -> std::io::Result<Vec<String>> {
+        enum_labels(&basic_ioctl(&self.f, sys::SNDCTL_DSP_GET_RECSRC_NAMES)?)
+    }
+
+    pub fn record_source(&self) -> std::io::Result<u32> {
+        c_int_to_u32(basic_ioctl(&self.f, sys::SNDCTL_DSP_GET_RECSRC)?)
     }
 
-    pub fn volume_play_set(&self, percent: u8) -> std::io::Result<()> {
-        let v: libc::c_int = percent.into();
-        basic_ioctl_inout(&self.f, sys::SNDCTL_DSP_SETPLAYVOL, v)?;
+    pub fn record_source_set(&self, index: u32) -> std::io::Result<()> {
+        let v = u32_to_c_int(index)?;
+        basic_ioctl_inout(&self.f, sys::SNDCTL_DSP_SET_RECSRC, v)?;
+        Ok(())
+    }
+
+    /**
+     * The names of the outputs this device can play to (e.g., "speaker" or
+     * "headphones"), in order; a target is selected by its index in this
+     * list.
+     */
+    pub fn play_targets(&self) -> std::io::Result<Vec<String>> {
+        enum_labels(&basic_ioctl(&self.f, sys::SNDCTL_DSP_GET_PLAYTGT_NAMES)?)
+    }
+
+    pub fn play_target(&self) -> std::io::Result<u32> {
+        c_int_to_u32(basic_ioctl(&self.f, sys::SNDCTL_DSP_GET_PLAYTGT)?)
+    }
+
+    pub fn play_target_set(&self, index: u32) -> std::io::Result<()> {
+        let v = u32_to_c_int(index)?;
+        basic_ioctl_inout(&self.f, sys::SNDCTL_DSP_SET_PLAYTGT, v)?;
         Ok(())
     }
 
@@ -88,6 +291,47 @@ impl Dsp {
         basic_ioctl(&self.f, sys::SNDCTL_DSP_GETISPACE)
     }
 
+    /**
+     * Set the number of bytes of buffer space (for playback) or buffered data
+     * (for recording) that must be available before the device is reported
+     * as ready by poll(2).
+     */
+    pub fn low_water_set(&self, bytes: u32) -> std::io::Result<()> {
+        if bytes == 0 {
+            return Err(std::io::Error::from_raw_os_error(libc::EINVAL));
+        }
+
+        let v = u32_to_c_int(bytes)?;
+        basic_ioctl_inout(&self.f, sys::SNDCTL_DSP_LOW_WATER, v)?;
+        Ok(())
+    }
+
+    /**
+     * Wait until at least the low water mark worth of play buffer space is
+     * available, so that a subsequent play() of that size will not block.
+     * Returns false if the timeout expired first; a timeout of None waits
+     * forever.
+     */
+    pub fn wait_writable(
+        &self,
+        timeout: Option<Duration>,
+    ) -> std::io::Result<bool> {
+        poll_one(&self.f, libc::POLLOUT, timeout)
+    }
+
+    /**
+     * Wait until at least the low water mark worth of recorded data is
+     * available to read.  Returns false if the timeout expired first; a
+     * timeout of None waits forever.  The device must have been opened for
+     * reading.
+     */
+    pub fn wait_readable(
+        &self,
+        timeout: Option<Duration>,
+    ) -> std::io::Result<bool> {
+        poll_one(&self.f, libc::POLLIN, timeout)
+    }
+
     pub fn channels(&self) -> std::io::Result<u32> {
         let v = basic_ioctl_inout(&self.f, sys::SNDCTL_DSP_CHANNELS, 0i32)?;
         if v == 0 {
@@ -97,7 +341,7 @@ impl Dsp {
             return Err(std::io::Error::from_raw_os_error(libc::EINVAL));
         }
 
-        Ok(v.try_into().unwrap())
+        c_int_to_u32(v)
     }
 
     pub fn channels_set(&self, count: u32) -> std::io::Result<()> {
@@ -110,30 +354,42 @@ impl Dsp {
             return Err(std::io::Error::from_raw_os_error(libc::EINVAL));
         }
 
-        let v: libc::c_int = basic_ioctl_inout(
-            &self.f,
-            sys::SNDCTL_DSP_CHANNELS,
-            count.try_into().unwrap(),
-        )?;
+        let count = u32_to_c_int(count)?;
+        let v: libc::c_int =
+            basic_ioctl_inout(&self.f, sys::SNDCTL_DSP_CHANNELS, count)?;
 
-        if v != count.try_into().unwrap() {
+        if v != count {
             return Err(std::io::Error::from_raw_os_error(libc::EINVAL));
         }
 
         Ok(())
     }
 
+    pub fn trigger(&self) -> std::io::Result<sys::Trigger> {
+        let v: libc::c_int = basic_ioctl(&self.f, sys::SNDCTL_DSP_GETTRIGGER)?;
+        Ok(sys::Trigger::from_bits_truncate(v))
+    }
+
+    /**
+     * Enable or disable the input and output engines.  Disabling output
+     * pauses playback without discarding audio that has already been queued;
+     * re-enabling it resumes from where it stopped.
+     */
+    pub fn trigger_set(&self, trigger: sys::Trigger) -> std::io::Result<()> {
+        basic_ioctl_inout(&self.f, sys::SNDCTL_DSP_SETTRIGGER, trigger.bits())?;
+        Ok(())
+    }
+
     pub fn formats(&self) -> std::io::Result<sys::AudioFormats> {
-        Ok(sys::AudioFormats::from_bits(basic_ioctl(
+        Ok(sys::AudioFormats::from_bits_retain(basic_ioctl(
             &self.f,
             sys::SNDCTL_DSP_GETFMTS,
-        )?)
-        .unwrap())
+        )?))
     }
 
     pub fn format(&self) -> std::io::Result<sys::AudioFormats> {
         let v = basic_ioctl_inout(&self.f, sys::SNDCTL_DSP_SETFMT, 0i32)?;
-        Ok(sys::AudioFormats::from_bits(v).unwrap())
+        Ok(sys::AudioFormats::from_bits_retain(v))
     }
 
     pub fn format_set(&self, format: sys::AudioFormats) -> std::io::Result<()> {
@@ -149,7 +405,7 @@ impl Dsp {
         let v: libc::c_int =
             basic_ioctl_inout(&self.f, sys::SNDCTL_DSP_SETFMT, bits)?;
 
-        if v != bits.try_into().unwrap() {
+        if v != bits {
             return Err(std::io::Error::from_raw_os_error(libc::EINVAL));
         }
 
@@ -158,12 +414,12 @@ impl Dsp {
 
     pub fn delay(&self) -> std::io::Result<usize> {
         let v: libc::c_int = basic_ioctl(&self.f, sys::SNDCTL_DSP_GETODELAY)?;
-        Ok(v.try_into().unwrap())
+        Ok(c_int_to_u32(v)? as usize)
     }
 
     pub fn speed(&self) -> std::io::Result<u32> {
         let v = basic_ioctl_inout(&self.f, sys::SNDCTL_DSP_SPEED, 0i32)?;
-        Ok(v.try_into().unwrap())
+        c_int_to_u32(v)
     }
 
     pub fn speed_set(&self, speed: u32) -> std::io::Result<()> {
@@ -176,33 +432,108 @@ impl Dsp {
             return Err(std::io::Error::from_raw_os_error(libc::EINVAL));
         }
 
-        let v: libc::c_int = basic_ioctl_inout(
-            &self.f,
-            sys::SNDCTL_DSP_SPEED,
-            speed.try_into().unwrap(),
-        )?;
+        let speed = u32_to_c_int(speed)?;
+        let v: libc::c_int =
+            basic_ioctl_inout(&self.f, sys::SNDCTL_DSP_SPEED, speed)?;
 
-        if v != speed.try_into().unwrap() {
+        if v != speed {
             return Err(std::io::Error::from_raw_os_error(libc::EINVAL));
         }
 
         Ok(())
     }
 
-    pub fn play(&self, buf: &[u8]) -> std::io::Result<()> {
+    /**
+     * Put the file descriptor in (or take it out of) non-blocking mode.  In
+     * non-blocking mode, play_some() and record() fail with EAGAIN rather
+     * than waiting for buffer space or data.  play() fails with EAGAIN only if
+     * the device accepts none of the buffer; once part of it has been
+     * written, it waits for room for the rest.
+     */
+    pub fn nonblocking_set(&self, nonblocking: bool) -> std::io::Result<()> {
         let fd = self.f.as_raw_fd();
 
-        let len = buf.len();
-        let buf = buf.as_ptr();
+        let fl = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        if fl < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        let nfl = if nonblocking {
+            fl | libc::O_NONBLOCK
+        } else {
+            fl & !libc::O_NONBLOCK
+        };
+
+        if nfl != fl && unsafe { libc::fcntl(fd, libc::F_SETFL, nfl) } < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
 
-        let wsz = unsafe { libc::write(fd, buf as *const c_void, len) };
+    /**
+     * Write as much of the buffer as the device will accept, returning the
+     * number of bytes written.
+     */
+    pub fn play_some(&self, buf: &[u8]) -> std::io::Result<usize> {
+        let fd = self.f.as_raw_fd();
+
+        let wsz = unsafe {
+            libc::write(fd, buf.as_ptr() as *const c_void, buf.len())
+        };
         if wsz < 0 {
             return Err(std::io::Error::last_os_error());
         }
 
-        /*
-         * XXX Check write size?
-         */
+        Ok(wsz as usize)
+    }
+
+    /**
+     * Read recorded audio into the buffer, returning the number of bytes read.
+     * The device must have been opened for recording.
+     */
+    pub fn record(&self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let fd = self.f.as_raw_fd();
+
+        let rsz = unsafe {
+            libc::read(fd, buf.as_mut_ptr() as *mut c_void, buf.len())
+        };
+        if rsz < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        Ok(rsz as usize)
+    }
+
+    /**
+     * Write the whole buffer, continuing after any short write.
+     */
+    pub fn play(&self, buf: &[u8]) -> std::io::Result<()> {
+        let mut buf = buf;
+        let mut started = false;
+
+        while !buf.is_empty() {
+            match self.play_some(buf) {
+                Ok(n) => {
+                    buf = &buf[n..];
+                    started = true;
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e)
+                    if started
+                        && e.kind() == std::io::ErrorKind::WouldBlock =>
+                {
+                    /*
+                     * Part of the buffer has been queued already, so failing
+                     * now would lose track of how much; wait for room for
+                     * the rest instead.
+                     */
+                    self.wait_writable(None)?;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
         Ok(())
     }
 
@@ -212,7 +543,99 @@ impl Dsp {
     //}
 }
 
+/**
+ * The device named by the AUDIODEV environment variable.
+ */
+pub(crate) enum AudioDev {
+    Path(String),
+    Number(u32),
+}
+
+impl AudioDev {
+    /**
+     * Read AUDIODEV, if it is set and not empty.  A value that parses as a
+     * number is an audio device number from the mixer; anything else is a
+     * path.
+     */
+    pub(crate) fn from_env() -> Option<Self> {
+        let v = std::env::var(AUDIODEV_ENV).ok().filter(|v| !v.is_empty())?;
+        Some(match v.parse::<u32>() {
+            Ok(n) => AudioDev::Number(n),
+            Err(_) => AudioDev::Path(v),
+        })
+    }
+
+    /**
+     * The path of the DSP device node for this device.
+     */
+    pub(crate) fn dsp_path(self) -> std::io::Result<String> {
+        match self {
+            AudioDev::Path(path) => Ok(path),
+            AudioDev::Number(n) => Ok(Mixer::open()?.audioinfo(n)?.devnode),
+        }
+    }
+}
+
+/**
+ * Find the device the mixer marks as the default for the given direction.
+ */
+fn default_devnode(mixer: &Mixer, direction: Direction) -> Option<String> {
+    let need = match direction {
+        Direction::Play => sys::AudioCaps::PCM_CAP_OUTPUT,
+        Direction::Record => sys::AudioCaps::PCM_CAP_INPUT,
+        Direction::Duplex => {
+            sys::AudioCaps::PCM_CAP_OUTPUT | sys::AudioCaps::PCM_CAP_INPUT
+        }
+    } | sys::AudioCaps::PCM_CAP_DEFAULT;
+
+    mixer
+        .audio_devices()
+        .ok()?
+        .filter_map(Result::ok)
+        .find(|info| {
+            info.caps.contains(need)
+                && !info.caps.contains(sys::AudioCaps::PCM_CAP_SHADOW)
+        })
+        .map(|info| info.devnode)
+}
+
+/**
+ * Extract the labels from an enumerated control description.  Each label is
+ * a NUL-terminated string within "strings", at the offset given by the
+ * corresponding entry in "strindex".
+ */
+fn enum_labels(ei: &sys::oss_mixer_enuminfo) -> std::io::Result<Vec<String>> {
+    let n = c_int_to_u32(ei.nvalues)? as usize;
+    if n > sys::OSS_ENUM_MAXVALUE {
+        return Err(std::io::Error::from_raw_os_error(libc::EINVAL));
+    }
+
+    let mut labels = Vec::with_capacity(n);
+    for &i in &ei.strindex[..n] {
+        let i = match usize::try_from(i) {
+            Ok(i) if i < ei.strings.len() => i,
+            _ => return Err(std::io::Error::from_raw_os_error(libc::EINVAL)),
+        };
+        labels.push(c_chars_to_string(&ei.strings[i..]));
+    }
+
+    Ok(labels)
+}
+
+impl AsFd for Dsp {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.f.as_fd()
+    }
+}
+
+impl AsRawFd for Dsp {
+    fn as_raw_fd(&self) -> RawFd {
+        self.f.as_raw_fd()
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ErrorInfo {
     pub play_underruns: u32,
     pub rec_overruns: u32,
@@ -0,0 +1,616 @@
+/*
+ * Dynamics processing: a look-ahead brickwall limiter and a feed-forward
+ * compressor.  Both link their channels, so that the stereo image does not
+ * shift when one channel is louder than the other, and both allocate all of
+ * their storage up front so that they may be used in a real-time callback.
+ */
+
+use std::{collections::VecDeque, time::Duration};
+
+use crate::{
+    pipeline::{Format, Processor},
+    sample::SampleFormat,
+    stream::StreamConfig,
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LimiterConfig {
+    /**
+     * The highest level the output may reach, in dBFS.
+     */
+    pub ceiling_db: f64,
+    /**
+     * How far ahead the limiter looks for peaks.  The output is delayed by
+     * this much, and gain reduction is spread over it.
+     */
+    pub lookahead: Duration,
+    /**
+     * How long the gain takes to recover after a peak has passed.
+     */
+    pub release: Duration,
+}
+
+impl Default for LimiterConfig {
+    fn default() -> Self {
+        LimiterConfig {
+            ceiling_db: -0.3,
+            lookahead: Duration::from_millis(5),
+            release: Duration::from_millis(100),
+        }
+    }
+}
+
+/**
+ * A brickwall limiter: no sample leaves it above the ceiling.  Each frame is
+ * held in a delay line while the limiter works out the gain it needs; the
+ * required gain is the minimum over the look-ahead window, smoothed by a
+ * moving average of the same length, which both avoids distortion and
+ * guarantees that the gain has come down fully by the time the peak emerges.
+ *
+ * Limit floating point mixes with process_f32() (or encode_f32()) before they
+ * are converted to an integer format, since samples beyond full scale would
+ * otherwise be clipped by the conversion.
+ */
+#[derive(Debug, Clone)]
+pub struct Limiter {
+    format: SampleFormat,
+    channels: usize,
+    rate: u32,
+    ceiling: f32,
+    release: f64,
+
+    len: usize,
+    /*
+     * Delayed samples, as a ring of "len - 1" frames.
+     */
+    delay: Vec<f32>,
+    delay_pos: usize,
+    /*
+     * The required gain of each recent frame, as a monotonic queue of (frame
+     * number, gain) from which the window minimum is read.
+     */
+    minq: VecDeque<(u64, f32)>,
+    /*
+     * The recent window minimums, as a ring, and their sum.
+     */
+    mins: Vec<f32>,
+    mins_pos: usize,
+    mins_sum: f64,
+    n: u64,
+    gain: f32,
+
+    scratch: Vec<f32>,
+}
+
+impl Limiter {
+    /**
+     * Fails with EINVAL if the ceiling is above 0 dBFS or is not finite.
+     */
+    pub fn new(
+        format: SampleFormat,
+        channels: usize,
+        rate: u32,
+        config: &LimiterConfig,
+    ) -> std::io::Result<Self> {
+        if !config.ceiling_db.is_finite() || config.ceiling_db > 0.0 {
+            return Err(std::io::Error::from_raw_os_error(libc::EINVAL));
+        }
+
+        let frate = f64::from(rate);
+        let len =
+            ((config.lookahead.as_secs_f64() * frate).round() as usize).max(1);
+
+        Ok(Limiter {
+            format,
+            channels,
+            rate,
+            ceiling: db_to_gain(config.ceiling_db),
+            release: time_coefficient(config.release, frate),
+            len,
+            delay: vec![0.0; (len - 1) * channels],
+            delay_pos: 0,
+            minq: VecDeque::with_capacity(len + 1),
+            mins: vec![1.0; len],
+            mins_pos: 0,
+            mins_sum: len as f64,
+            n: 0,
+            gain: 1.0,
+            scratch: vec![0.0; channels],
+        })
+    }
+
+    /**
+     * Create a limiter for audio in the format used by a Stream.  Fails with
+     * EINVAL if the format is not one we can convert.
+     */
+    pub fn for_stream(
+        stream: &StreamConfig,
+        config: &LimiterConfig,
+    ) -> std::io::Result<Self> {
+        let (format, channels, rate) = stream_params(stream)?;
+        Self::new(format, channels, rate, config)
+    }
+
+    /**
+     * The number of frames by which the output lags the input.
+     */
+    pub fn latency_frames(&self) -> usize {
+        self.len - 1
+    }
+
+    /**
+     * The gain reduction currently applied, in dB (zero or negative).
+     */
+    pub fn gain_db(&self) -> f64 {
+        20.0 * f64::from(self.gain).log10()
+    }
+
+    /**
+     * Limit a buffer of interleaved frames in place.  Any partial frame at
+     * the end of the buffer is left alone.
+     */
+    pub fn process(&mut self, buf: &mut [u8]) {
+        let ss = self.format.size();
+        let fs = ss * self.channels;
+        if fs == 0 {
+            return;
+        }
+
+        let mut frame = std::mem::take(&mut self.scratch);
+        for bytes in buf.chunks_exact_mut(fs) {
+            for (v, s) in frame.iter_mut().zip(bytes.chunks_exact(ss)) {
+                *v = self.format.decode(s);
+            }
+            self.frame(&mut frame);
+            for (v, s) in frame.iter().zip(bytes.chunks_exact_mut(ss)) {
+                self.format.encode(*v, s);
+            }
+        }
+        self.scratch = frame;
+    }
+
+    /**
+     * Limit a buffer of interleaved floating point frames in place.
+     */
+    pub fn process_f32(&mut self, buf: &mut [f32]) {
+        if self.channels == 0 {
+            return;
+        }
+
+        for frame in buf.chunks_exact_mut(self.channels) {
+            self.frame(frame);
+        }
+    }
+
+    /**
+     * Limit floating point frames and encode them into the limiter's sample
+     * format.  Returns the number of frames encoded, which is as many whole
+     * frames as fit in both buffers.
+     */
+    pub fn encode_f32(&mut self, input: &[f32], output: &mut [u8]) -> usize {
+        let ss = self.format.size();
+        let fs = ss * self.channels;
+        if fs == 0 {
+            return 0;
+        }
+
+        let mut frame = std::mem::take(&mut self.scratch);
+        let mut n = 0;
+        for (src, dst) in
+            input.chunks_exact(self.channels).zip(output.chunks_exact_mut(fs))
+        {
+            frame.copy_from_slice(src);
+            self.frame(&mut frame);
+            for (v, s) in frame.iter().zip(dst.chunks_exact_mut(ss)) {
+                self.format.encode(*v, s);
+            }
+            n += 1;
+        }
+        self.scratch = frame;
+        n
+    }
+
+    /**
+     * Discard the delayed audio and any gain reduction in progress.
+     */
+    pub fn reset(&mut self) {
+        self.delay.fill(0.0);
+        self.minq.clear();
+        self.mins.fill(1.0);
+        self.mins_sum = self.len as f64;
+        self.gain = 1.0;
+    }
+
+    fn frame(&mut self, frame: &mut [f32]) {
+        let peak = frame.iter().fold(0.0f32, |m, v| m.max(v.abs()));
+        let need = if peak > self.ceiling { self.ceiling / peak } else { 1.0 };
+
+        /*
+         * Window minimum of the required gain over the last "len" frames.
+         */
+        while self.minq.back().is_some_and(|&(_, g)| g >= need) {
+            self.minq.pop_back();
+        }
+        self.minq.push_back((self.n, need));
+        while self
+            .minq
+            .front()
+            .is_some_and(|&(i, _)| i + (self.len as u64) <= self.n)
+        {
+            self.minq.pop_front();
+        }
+        let min = self.minq.front().map(|&(_, g)| g).unwrap_or(1.0);
+        self.n += 1;
+
+        /*
+         * Moving average of the minimum over the same window.
+         */
+        self.mins_sum += f64::from(min) - f64::from(self.mins[self.mins_pos]);
+        self.mins[self.mins_pos] = min;
+        self.mins_pos = (self.mins_pos + 1) % self.len;
+        let target = ((self.mins_sum / self.len as f64) as f32).min(1.0);
+
+        /*
+         * Fall immediately, since the average already ramps down smoothly,
+         * but recover slowly.  Slowing the rise can only lower the gain, so
+         * it does not break the guarantee.
+         */
+        self.gain = if target < self.gain {
+            target
+        } else {
+            target + (self.gain - target) * self.release as f32
+        };
+
+        /*
+         * Swap the frame with the oldest one in the delay line, then apply
+         * the gain to that.
+         */
+        if self.len > 1 {
+            let at = self.delay_pos * self.channels;
+            frame.swap_with_slice(&mut self.delay[at..at + self.channels]);
+            self.delay_pos = (self.delay_pos + 1) % (self.len - 1);
+        }
+
+        /*
+         * Rounding in the moving average could leave a sample a hair over
+         * the ceiling, so clamp as a last resort.
+         */
+        for v in frame.iter_mut() {
+            *v = (*v * self.gain).clamp(-self.ceiling, self.ceiling);
+        }
+    }
+}
+
+impl Processor for Limiter {
+    fn format(&self) -> Option<Format> {
+        Some(Format::new(self.channels, self.rate))
+    }
+
+    fn process_block(&mut self, buf: &mut [f32]) {
+        self.process_f32(buf)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompressorConfig {
+    /**
+     * The level in dBFS above which gain is reduced.
+     */
+    pub threshold_db: f64,
+    /**
+     * The input level must rise by this many dB above the threshold for the
+     * output to rise by 1 dB.
+     */
+    pub ratio: f64,
+    /**
+     * The width in dB of the region around the threshold over which the ratio
+     * is phased in.  Zero gives a hard knee.
+     */
+    pub knee_db: f64,
+    pub attack: Duration,
+    pub release: Duration,
+    /**
+     * Gain applied after compression, in dB.
+     */
+    pub makeup_db: f64,
+}
+
+impl Default for CompressorConfig {
+    fn default() -> Self {
+        CompressorConfig {
+            threshold_db: -18.0,
+            ratio: 4.0,
+            knee_db: 6.0,
+            attack: Duration::from_millis(10),
+            release: Duration::from_millis(150),
+            makeup_db: 0.0,
+        }
+    }
+}
+
+/**
+ * A feed-forward compressor.  The peak level of each frame drives a gain
+ * computer with a soft knee, and the resulting gain reduction is smoothed with
+ * separate attack and release times.  A compressor does not stop peaks
+ * getting through; follow it with a Limiter if the output must not clip.
+ */
+#[derive(Debug, Clone)]
+pub struct Compressor {
+    format: SampleFormat,
+    channels: usize,
+    rate: u32,
+    threshold: f64,
+    slope: f64,
+    knee: f64,
+    attack: f64,
+    release: f64,
+    makeup: f64,
+    /*
+     * The smoothed gain reduction, in dB (zero or positive).
+     */
+    reduction: f64,
+}
+
+impl Compressor {
+    /**
+     * Fails with EINVAL if the ratio is less than 1, or the knee is negative,
+     * or any level is not finite.
+     */
+    pub fn new(
+        format: SampleFormat,
+        channels: usize,
+        rate: u32,
+        config: &CompressorConfig,
+    ) -> std::io::Result<Self> {
+        let c = config;
+        if c.ratio.is_nan()
+            || c.ratio < 1.0
+            || c.knee_db.is_nan()
+            || c.knee_db < 0.0
+            || !c.threshold_db.is_finite()
+            || !c.knee_db.is_finite()
+            || !c.makeup_db.is_finite()
+        {
+            return Err(std::io::Error::from_raw_os_error(libc::EINVAL));
+        }
+
+        let frate = f64::from(rate);
+        Ok(Compressor {
+            format,
+            channels,
+            rate,
+            threshold: c.threshold_db,
+            slope: 1.0 - 1.0 / c.ratio,
+            knee: c.knee_db,
+            attack: time_coefficient(c.attack, frate),
+            release: time_coefficient(c.release, frate),
+            makeup: c.makeup_db,
+            reduction: 0.0,
+        })
+    }
+
+    /**
+     * Create a compressor for audio in the format used by a Stream.  Fails
+     * with EINVAL if the format is not one we can convert.
+     */
+    pub fn for_stream(
+        stream: &StreamConfig,
+        config: &CompressorConfig,
+    ) -> std::io::Result<Self> {
+        let (format, channels, rate) = stream_params(stream)?;
+        Self::new(format, channels, rate, config)
+    }
+
+    /**
+     * The gain reduction currently applied, in dB (zero or negative), not
+     * counting the makeup gain.
+     */
+    pub fn gain_db(&self) -> f64 {
+        -self.reduction
+    }
+
+    /**
+     * Compress a buffer of interleaved frames in place.  Any partial frame
+     * at the end of the buffer is left alone.
+     */
+    pub fn process(&mut self, buf: &mut [u8]) {
+        let ss = self.format.size();
+        let fs = ss * self.channels;
+        if fs == 0 {
+            return;
+        }
+
+        for bytes in buf.chunks_exact_mut(fs) {
+            let peak = bytes
+                .chunks_exact(ss)
+                .fold(0.0f32, |m, s| m.max(self.format.decode(s).abs()));
+            let g = self.gain(peak);
+            for s in bytes.chunks_exact_mut(ss) {
+                let v = self.format.decode(s);
+                self.format.encode(v * g, s);
+            }
+        }
+    }
+
+    /**
+     * Compress a buffer of interleaved floating point frames in place.
+     */
+    pub fn process_f32(&mut self, buf: &mut [f32]) {
+        if self.channels == 0 {
+            return;
+        }
+
+        for frame in buf.chunks_exact_mut(self.channels) {
+            let peak = frame.iter().fold(0.0f32, |m, v| m.max(v.abs()));
+            let g = self.gain(peak);
+            frame.iter_mut().for_each(|v| *v *= g);
+        }
+    }
+
+    /**
+     * Update the gain reduction for a frame with the given peak level, and
+     * return the linear gain to apply to it.
+     */
+    fn gain(&mut self, peak: f32) -> f32 {
+        let level = 20.0 * f64::from(peak).max(1e-10).log10();
+        let over = level - self.threshold;
+
+        let want = if 2.0 * over <= -self.knee {
+            0.0
+        } else if 2.0 * over >= self.knee {
+            over * self.slope
+        } else {
+            let x = over + self.knee / 2.0;
+            self.slope * x * x / (2.0 * self.knee)
+        };
+
+        let coef =
+            if want > self.reduction { self.attack } else { self.release };
+        self.reduction = want + (self.reduction - want) * coef;
+
+        db_to_gain(self.makeup - self.reduction)
+    }
+}
+
+impl Processor for Compressor {
+    fn format(&self) -> Option<Format> {
+        Some(Format::new(self.channels, self.rate))
+    }
+
+    fn process_block(&mut self, buf: &mut [f32]) {
+        self.process_f32(buf)
+    }
+}
+
+fn stream_params(
+    config: &StreamConfig,
+) -> std::io::Result<(SampleFormat, usize, u32)> {
+    let format = SampleFormat::from_audio_format(config.format)
+        .ok_or_else(|| std::io::Error::from_raw_os_error(libc::EINVAL))?;
+    Ok((format, config.channels as usize, config.rate))
+}
+
+/**
+ * The coefficient of a one-pole smoother that covers about 63% of a change
+ * in the given time.
+ */
+fn time_coefficient(t: Duration, rate: f64) -> f64 {
+    let frames = t.as_secs_f64() * rate;
+    if frames <= 0.0 {
+        0.0
+    } else {
+        (-1.0 / frames).exp()
+    }
+}
+
+fn db_to_gain(db: f64) -> f32 {
+    10f64.powf(db / 20.0) as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 48000;
+
+    fn limiter(channels: usize) -> Limiter {
+        Limiter::new(
+            SampleFormat::Float,
+            channels,
+            RATE,
+            &LimiterConfig::default(),
+        )
+        .unwrap()
+    }
+
+    /**
+     * Deterministic noise in [-1, 1).
+     */
+    fn noise(n: usize) -> impl Iterator<Item = f32> {
+        let mut x = 0x2545_f491_4f6c_dd1du64;
+        (0..n).map(move |_| {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            (x >> 40) as f32 / (1u64 << 23) as f32 - 1.0
+        })
+    }
+
+    #[test]
+    fn limiter_ceiling() {
+        let ceiling = db_to_gain(LimiterConfig::default().ceiling_db);
+        let mut l = limiter(2);
+
+        /*
+         * Noise whose level jumps about by up to 24 dB, with isolated
+         * spikes, in blocks of assorted sizes.
+         */
+        let mut buf: Vec<f32> = noise(2 * RATE as usize)
+            .enumerate()
+            .map(|(i, v)| {
+                let level = [0.1, 16.0, 0.5, 4.0][(i / 7919) % 4];
+                if i % 4001 == 0 {
+                    v.signum() * 30.0
+                } else {
+                    v * level
+                }
+            })
+            .collect();
+        for chunk in buf.chunks_mut(2 * 317) {
+            l.process_f32(chunk);
+        }
+
+        for (i, v) in buf.iter().enumerate() {
+            assert!(v.abs() <= ceiling, "sample {i}: {v}");
+        }
+        assert!(l.gain_db() < 0.0);
+    }
+
+    #[test]
+    fn limiter_passes_quiet_audio() {
+        let mut l = limiter(1);
+        let latency = l.latency_frames();
+        assert_eq!(latency, 239);
+
+        let input: Vec<f32> = noise(1000).map(|v| v * 0.5).collect();
+        let mut buf = input.clone();
+        l.process_f32(&mut buf);
+
+        assert!(buf[..latency].iter().all(|&v| v == 0.0));
+        assert_eq!(buf[latency..], input[..1000 - latency]);
+        assert_eq!(l.gain_db(), 0.0);
+    }
+
+    #[test]
+    fn limiter_invalid_ceiling() {
+        for ceiling_db in [0.1, f64::NAN, f64::NEG_INFINITY] {
+            let config = LimiterConfig { ceiling_db, ..Default::default() };
+            assert!(
+                Limiter::new(SampleFormat::Float, 2, RATE, &config).is_err()
+            );
+        }
+    }
+
+    #[test]
+    fn compressor_steady_state() {
+        let config = CompressorConfig {
+            threshold_db: -20.0,
+            ratio: 4.0,
+            knee_db: 0.0,
+            makeup_db: 0.0,
+            ..Default::default()
+        };
+        let mut c =
+            Compressor::new(SampleFormat::Float, 1, RATE, &config).unwrap();
+
+        /*
+         * A constant level 20 dB over the threshold should be brought down
+         * to 5 dB over it.
+         */
+        let mut buf = vec![db_to_gain(0.0); RATE as usize];
+        c.process_f32(&mut buf);
+        assert!((c.gain_db() + 15.0).abs() < 0.01, "{}", c.gain_db());
+        let out = 20.0 * f64::from(buf[buf.len() - 1]).log10();
+        assert!((out + 15.0).abs() < 0.01, "{out}");
+    }
+}
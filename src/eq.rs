@@ -0,0 +1,575 @@
+/*
+ * Tone shaping with biquad filters, designed with the formulas from Robert
+ * Bristow-Johnson's "Cookbook formulae for audio EQ biquad filter
+ * coefficients".
+ */
+
+use std::{
+    f64::consts::TAU,
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    pipeline::{Format, Processor},
+    sample::SampleFormat,
+    stream::StreamConfig,
+};
+
+/*
+ * When a stage is given new coefficients, it moves to them gradually over
+ * this long, so that the change does not click.
+ */
+const RAMP_SECS: f64 = 0.01;
+
+/**
+ * A filter design.  Frequencies are in Hz and must lie strictly between zero
+ * and half the sample rate; gains are in dB.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Filter {
+    LowPass {
+        freq: f64,
+        q: f64,
+    },
+    /**
+     * E.g., to keep low frequencies away from small speakers.  A Q of
+     * 1/sqrt(2) gives a Butterworth response.
+     */
+    HighPass {
+        freq: f64,
+        q: f64,
+    },
+    /**
+     * A band pass with a peak gain of 0 dB.
+     */
+    BandPass {
+        freq: f64,
+        q: f64,
+    },
+    Notch {
+        freq: f64,
+        q: f64,
+    },
+    AllPass {
+        freq: f64,
+        q: f64,
+    },
+    /**
+     * A parametric peak (or dip, for a negative gain).
+     */
+    Peaking {
+        freq: f64,
+        q: f64,
+        gain_db: f64,
+    },
+    /**
+     * A shelf below "freq".  A slope of 1.0 is the steepest slope that does
+     * not overshoot.
+     */
+    LowShelf {
+        freq: f64,
+        slope: f64,
+        gain_db: f64,
+    },
+    HighShelf {
+        freq: f64,
+        slope: f64,
+        gain_db: f64,
+    },
+}
+
+/**
+ * The coefficients of a biquad, normalized so that a0 is 1.0; i.e., the
+ * filter computes y[n] = b0 x[n] + b1 x[n-1] + b2 x[n-2] - a1 y[n-1] -
+ * a2 y[n-2].
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Coefficients {
+    pub b0: f64,
+    pub b1: f64,
+    pub b2: f64,
+    pub a1: f64,
+    pub a2: f64,
+}
+
+impl Coefficients {
+    /**
+     * A filter that passes its input unchanged.
+     */
+    pub fn identity() -> Self {
+        Coefficients { b0: 1.0, b1: 0.0, b2: 0.0, a1: 0.0, a2: 0.0 }
+    }
+
+    /**
+     * Design a filter for the given sample rate.  Fails with EINVAL if the
+     * frequency is out of range, Q or the slope is not positive, or the gain
+     * is not finite.
+     */
+    pub fn design(filter: Filter, rate: u32) -> std::io::Result<Self> {
+        let einval = || std::io::Error::from_raw_os_error(libc::EINVAL);
+
+        let (freq, gain_db) = match filter {
+            Filter::LowPass { freq, q }
+            | Filter::HighPass { freq, q }
+            | Filter::BandPass { freq, q }
+            | Filter::Notch { freq, q }
+            | Filter::AllPass { freq, q } => {
+                if q.is_nan() || q <= 0.0 {
+                    return Err(einval());
+                }
+                (freq, 0.0)
+            }
+            Filter::Peaking { freq, q, gain_db } => {
+                if q.is_nan() || q <= 0.0 {
+                    return Err(einval());
+                }
+                (freq, gain_db)
+            }
+            Filter::LowShelf { freq, slope, gain_db }
+            | Filter::HighShelf { freq, slope, gain_db } => {
+                if slope.is_nan() || slope <= 0.0 {
+                    return Err(einval());
+                }
+                (freq, gain_db)
+            }
+        };
+        let nyquist = f64::from(rate) / 2.0;
+        if freq.is_nan() || freq <= 0.0 || freq >= nyquist {
+            return Err(einval());
+        }
+        if !gain_db.is_finite() {
+            return Err(einval());
+        }
+
+        let a = 10f64.powf(gain_db / 40.0);
+        let w0 = TAU * freq / f64::from(rate);
+        let (sin, cos) = w0.sin_cos();
+        let alpha_q = |q: f64| sin / (2.0 * q);
+
+        let [b0, b1, b2, a0, a1, a2] = match filter {
+            Filter::LowPass { q, .. } => {
+                let al = alpha_q(q);
+                let b = (1.0 - cos) / 2.0;
+                [b, 1.0 - cos, b, 1.0 + al, -2.0 * cos, 1.0 - al]
+            }
+            Filter::HighPass { q, .. } => {
+                let al = alpha_q(q);
+                let b = (1.0 + cos) / 2.0;
+                [b, -(1.0 + cos), b, 1.0 + al, -2.0 * cos, 1.0 - al]
+            }
+            Filter::BandPass { q, .. } => {
+                let al = alpha_q(q);
+                [al, 0.0, -al, 1.0 + al, -2.0 * cos, 1.0 - al]
+            }
+            Filter::Notch { q, .. } => {
+                let al = alpha_q(q);
+                [1.0, -2.0 * cos, 1.0, 1.0 + al, -2.0 * cos, 1.0 - al]
+            }
+            Filter::AllPass { q, .. } => {
+                let al = alpha_q(q);
+                [1.0 - al, -2.0 * cos, 1.0 + al, 1.0 + al, -2.0 * cos, 1.0 - al]
+            }
+            Filter::Peaking { q, .. } => {
+                let al = alpha_q(q);
+                [
+                    1.0 + al * a,
+                    -2.0 * cos,
+                    1.0 - al * a,
+                    1.0 + al / a,
+                    -2.0 * cos,
+                    1.0 - al / a,
+                ]
+            }
+            Filter::LowShelf { slope, .. }
+            | Filter::HighShelf { slope, .. } => {
+                let s = (a + 1.0 / a) * (1.0 / slope - 1.0) + 2.0;
+                if s < 0.0 {
+                    return Err(einval());
+                }
+                /*
+                 * This is 2 sqrt(A) alpha in the cookbook's terms.
+                 */
+                let al2 = a.sqrt() * sin * s.sqrt();
+                let (ap, am) = (a + 1.0, a - 1.0);
+
+                if let Filter::LowShelf { .. } = filter {
+                    [
+                        a * (ap - am * cos + al2),
+                        2.0 * a * (am - ap * cos),
+                        a * (ap - am * cos - al2),
+                        ap + am * cos + al2,
+                        -2.0 * (am + ap * cos),
+                        ap + am * cos - al2,
+                    ]
+                } else {
+                    [
+                        a * (ap + am * cos + al2),
+                        -2.0 * a * (am + ap * cos),
+                        a * (ap + am * cos - al2),
+                        ap - am * cos + al2,
+                        2.0 * (am - ap * cos),
+                        ap - am * cos - al2,
+                    ]
+                }
+            }
+        };
+
+        Ok(Coefficients {
+            b0: b0 / a0,
+            b1: b1 / a0,
+            b2: b2 / a0,
+            a1: a1 / a0,
+            a2: a2 / a0,
+        })
+    }
+
+    /**
+     * The gain of the filter at the given frequency, in dB.
+     */
+    pub fn response_db(&self, freq: f64, rate: u32) -> f64 {
+        let w = TAU * freq / f64::from(rate);
+        let (s1, c1) = w.sin_cos();
+        let (s2, c2) = (2.0 * w).sin_cos();
+
+        let nr = self.b0 + self.b1 * c1 + self.b2 * c2;
+        let ni = -(self.b1 * s1 + self.b2 * s2);
+        let dr = 1.0 + self.a1 * c1 + self.a2 * c2;
+        let di = -(self.a1 * s1 + self.a2 * s2);
+
+        10.0 * ((nr * nr + ni * ni) / (dr * dr + di * di)).log10()
+    }
+
+    fn step_towards(&mut self, target: &Self, frames: u32) {
+        let f = 1.0 / f64::from(frames);
+        self.b0 += (target.b0 - self.b0) * f;
+        self.b1 += (target.b1 - self.b1) * f;
+        self.b2 += (target.b2 - self.b2) * f;
+        self.a1 += (target.a1 - self.a1) * f;
+        self.a2 += (target.a2 - self.a2) * f;
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Stage {
+    current: Coefficients,
+    target: Coefficients,
+    remaining: u32,
+    /*
+     * Transposed direct form II state for each channel.
+     */
+    state: Vec<[f64; 2]>,
+}
+
+impl Stage {
+    fn retarget(&mut self, target: Coefficients, ramp: u32) {
+        self.target = target;
+        self.remaining = ramp;
+        if ramp == 0 {
+            self.current = target;
+        }
+    }
+
+    fn advance(&mut self) {
+        if self.remaining > 0 {
+            self.current.step_towards(&self.target, self.remaining);
+            self.remaining -= 1;
+        }
+    }
+
+    fn process(&mut self, channel: usize, x: f64) -> f64 {
+        let c = &self.current;
+        let z = &mut self.state[channel];
+        let y = c.b0 * x + z[0];
+        z[0] = c.b1 * x - c.a1 * y + z[1];
+        z[1] = c.b2 * x - c.a2 * y;
+        y
+    }
+}
+
+type Pending = Arc<Mutex<Vec<Option<Coefficients>>>>;
+
+/**
+ * A series of biquad filters applied to interleaved frames, with the same
+ * filters on every channel.  Stages are applied in the order they were added.
+ *
+ * Filters may be changed while audio is being processed, either directly
+ * with set() or from another thread through a FilterControl.  New
+ * coefficients take effect at the start of the next process() call, and are
+ * approached gradually over 10 ms to avoid clicks.  process() never waits for
+ * a FilterControl; if one is busy, its update is picked up next time.
+ */
+#[derive(Debug)]
+pub struct FilterChain {
+    format: SampleFormat,
+    rate: u32,
+    channels: usize,
+    ramp: u32,
+    stages: Vec<Stage>,
+    pending: Pending,
+}
+
+impl FilterChain {
+    pub fn new(format: SampleFormat, channels: usize, rate: u32) -> Self {
+        FilterChain {
+            format,
+            rate,
+            channels,
+            ramp: (f64::from(rate) * RAMP_SECS) as u32,
+            stages: Vec::new(),
+            pending: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /**
+     * Create a filter chain for audio in the format used by a Stream.  Fails
+     * with EINVAL if the format is not one we can convert.
+     */
+    pub fn for_stream(config: &StreamConfig) -> std::io::Result<Self> {
+        let format = SampleFormat::from_audio_format(config.format)
+            .ok_or_else(|| std::io::Error::from_raw_os_error(libc::EINVAL))?;
+
+        Ok(Self::new(format, config.channels as usize, config.rate))
+    }
+
+    /**
+     * Add a filter to the end of the chain, returning its index for use with
+     * set().  The new stage takes effect immediately.
+     */
+    pub fn push(&mut self, filter: Filter) -> std::io::Result<usize> {
+        let c = Coefficients::design(filter, self.rate)?;
+        self.stages.push(Stage {
+            current: c,
+            target: c,
+            remaining: 0,
+            state: vec![[0.0; 2]; self.channels],
+        });
+        self.pending.lock().unwrap().push(None);
+        Ok(self.stages.len() - 1)
+    }
+
+    pub fn len(&self) -> usize {
+        self.stages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stages.is_empty()
+    }
+
+    /**
+     * Change the filter at the given index.  Fails with EINVAL if there is
+     * no such stage, or if the filter is invalid.
+     */
+    pub fn set(&mut self, index: usize, filter: Filter) -> std::io::Result<()> {
+        let c = Coefficients::design(filter, self.rate)?;
+        let stage = self
+            .stages
+            .get_mut(index)
+            .ok_or_else(|| std::io::Error::from_raw_os_error(libc::EINVAL))?;
+        stage.retarget(c, self.ramp);
+        Ok(())
+    }
+
+    /**
+     * Return a handle that can change the filters from another thread.
+     */
+    pub fn control(&self) -> FilterControl {
+        FilterControl { rate: self.rate, pending: Arc::clone(&self.pending) }
+    }
+
+    /**
+     * Forget the filter history, as after a discontinuity in the audio.
+     */
+    pub fn reset(&mut self) {
+        for s in &mut self.stages {
+            s.state.fill([0.0; 2]);
+        }
+    }
+
+    fn apply_pending(&mut self) {
+        let Ok(mut pending) = self.pending.try_lock() else {
+            return;
+        };
+        for (stage, p) in self.stages.iter_mut().zip(pending.iter_mut()) {
+            if let Some(c) = p.take() {
+                stage.retarget(c, self.ramp);
+            }
+        }
+    }
+
+    /**
+     * Filter a buffer of interleaved frames in place.  Any partial frame at
+     * the end of the buffer is left alone.
+     */
+    pub fn process(&mut self, buf: &mut [u8]) {
+        self.apply_pending();
+
+        let ss = self.format.size();
+        let fs = ss * self.channels;
+        if fs == 0 || self.stages.is_empty() {
+            return;
+        }
+
+        for frame in buf.chunks_exact_mut(fs) {
+            for (c, s) in frame.chunks_exact_mut(ss).enumerate() {
+                let x = f64::from(self.format.decode(s));
+                let y =
+                    self.stages.iter_mut().fold(x, |x, st| st.process(c, x));
+                self.format.encode(y as f32, s);
+            }
+            self.stages.iter_mut().for_each(Stage::advance);
+        }
+    }
+
+    /**
+     * Filter a buffer of interleaved floating point frames in place.
+     */
+    pub fn process_f32(&mut self, buf: &mut [f32]) {
+        self.apply_pending();
+
+        if self.channels == 0 || self.stages.is_empty() {
+            return;
+        }
+
+        for frame in buf.chunks_exact_mut(self.channels) {
+            for (c, s) in frame.iter_mut().enumerate() {
+                let x = f64::from(*s);
+                let y =
+                    self.stages.iter_mut().fold(x, |x, st| st.process(c, x));
+                *s = y as f32;
+            }
+            self.stages.iter_mut().for_each(Stage::advance);
+        }
+    }
+}
+
+impl Processor for FilterChain {
+    fn format(&self) -> Option<Format> {
+        Some(Format::new(self.channels, self.rate))
+    }
+
+    fn process_block(&mut self, buf: &mut [f32]) {
+        self.process_f32(buf)
+    }
+}
+
+/**
+ * Changes the filters of a FilterChain that is in use on another thread; e.g.,
+ * from a user interface while a Stream is running.
+ */
+#[derive(Debug, Clone)]
+pub struct FilterControl {
+    rate: u32,
+    pending: Pending,
+}
+
+impl FilterControl {
+    /**
+     * Change the filter at the given index.  The coefficients are computed
+     * here, rather than on the audio thread.  Fails with EINVAL if there is
+     * no such stage, or if the filter is invalid.
+     */
+    pub fn set(&self, index: usize, filter: Filter) -> std::io::Result<()> {
+        let c = Coefficients::design(filter, self.rate)?;
+        let mut pending = self.pending.lock().unwrap();
+        let slot = pending
+            .get_mut(index)
+            .ok_or_else(|| std::io::Error::from_raw_os_error(libc::EINVAL))?;
+        *slot = Some(c);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 48000;
+
+    fn dc_and_nyquist(filter: Filter) -> (f64, f64) {
+        let c = Coefficients::design(filter, RATE).unwrap();
+        (c.response_db(0.0, RATE), c.response_db(f64::from(RATE) / 2.0, RATE))
+    }
+
+    /**
+     * Run a constant and an alternating signal through a single filter and
+     * return their amplitudes once the filter has settled.
+     */
+    fn run_dc_and_nyquist(filter: Filter) -> (f32, f32) {
+        let mut dc = FilterChain::new(SampleFormat::Float, 2, RATE);
+        dc.push(filter).unwrap();
+        let mut buf = [0.5f32; 2 * 4800];
+        dc.process_f32(&mut buf);
+        let dc_out = buf[buf.len() - 1];
+
+        let mut ny = FilterChain::new(SampleFormat::Float, 2, RATE);
+        ny.push(filter).unwrap();
+        let mut buf: Vec<f32> = (0..4800)
+            .flat_map(|i| {
+                let s = if i % 2 == 0 { 0.5 } else { -0.5 };
+                [s, s]
+            })
+            .collect();
+        ny.process_f32(&mut buf);
+        let ny_out = buf[buf.len() - 1].abs();
+
+        (dc_out, ny_out)
+    }
+
+    #[test]
+    fn low_and_high_pass() {
+        let q = std::f64::consts::FRAC_1_SQRT_2;
+
+        let (dc, ny) = dc_and_nyquist(Filter::LowPass { freq: 1000.0, q });
+        assert!(dc.abs() < 1e-9, "{dc}");
+        assert!(ny < -120.0, "{ny}");
+        let (dc, ny) = run_dc_and_nyquist(Filter::LowPass { freq: 1000.0, q });
+        assert!((dc - 0.5).abs() < 1e-4, "{dc}");
+        assert!(ny < 1e-4, "{ny}");
+
+        let (dc, ny) = dc_and_nyquist(Filter::HighPass { freq: 1000.0, q });
+        assert!(dc < -120.0, "{dc}");
+        assert!(ny.abs() < 1e-9, "{ny}");
+        let (dc, ny) = run_dc_and_nyquist(Filter::HighPass { freq: 1000.0, q });
+        assert!(dc.abs() < 1e-4, "{dc}");
+        assert!((ny - 0.5).abs() < 1e-4, "{ny}");
+    }
+
+    #[test]
+    fn shelves() {
+        let low = Filter::LowShelf { freq: 1000.0, slope: 1.0, gain_db: 6.0 };
+        let (dc, ny) = dc_and_nyquist(low);
+        assert!((dc - 6.0).abs() < 1e-6, "{dc}");
+        assert!(ny.abs() < 0.01, "{ny}");
+
+        let high =
+            Filter::HighShelf { freq: 1000.0, slope: 1.0, gain_db: -6.0 };
+        let (dc, ny) = dc_and_nyquist(high);
+        assert!(dc.abs() < 0.01, "{dc}");
+        assert!((ny + 6.0).abs() < 1e-6, "{ny}");
+    }
+
+    #[test]
+    fn peaking_and_notch() {
+        let peak = Filter::Peaking { freq: 1000.0, q: 1.0, gain_db: 9.0 };
+        let c = Coefficients::design(peak, RATE).unwrap();
+        assert!((c.response_db(1000.0, RATE) - 9.0).abs() < 1e-6);
+        let (dc, ny) = dc_and_nyquist(peak);
+        assert!(dc.abs() < 1e-9 && ny.abs() < 1e-9, "{dc} {ny}");
+
+        let (dc, ny) = dc_and_nyquist(Filter::Notch { freq: 1000.0, q: 1.0 });
+        assert!(dc.abs() < 1e-9 && ny.abs() < 1e-9, "{dc} {ny}");
+    }
+
+    #[test]
+    fn invalid() {
+        for filter in [
+            Filter::LowPass { freq: 0.0, q: 1.0 },
+            Filter::LowPass { freq: 24000.0, q: 1.0 },
+            Filter::HighPass { freq: 1000.0, q: 0.0 },
+            Filter::Peaking { freq: 1000.0, q: 1.0, gain_db: f64::NAN },
+            Filter::LowShelf { freq: 1000.0, slope: -1.0, gain_db: 3.0 },
+        ] {
+            assert!(Coefficients::design(filter, RATE).is_err(), "{filter:?}");
+        }
+    }
+}
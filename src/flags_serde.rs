@@ -0,0 +1,131 @@
+/*
+ * Serialize the bitflags types as a list of the names of the flags that are
+ * set, rather than as a bare integer, so that serialized device descriptions
+ * are self-explanatory.  Any set bits that do not correspond to a named flag
+ * (e.g., the revision level in AudioCaps) are appended as a single hexadecimal
+ * string, so that the value survives a round trip intact.
+ */
+
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{
+    sunaudio::sys::{HwFeatures, InputPorts, OutputPorts, SwFeatures},
+    sys::{AudioBusy, AudioCaps, AudioFormats, Trigger},
+};
+
+macro_rules! flags_serde {
+    ($($t:ty),* $(,)?) => {
+        $(
+            impl Serialize for $t {
+                fn serialize<S: Serializer>(
+                    &self,
+                    s: S,
+                ) -> Result<S::Ok, S::Error> {
+                    let mut names: Vec<String> =
+                        self.iter_names().map(|(n, _)| n.to_string()).collect();
+
+                    let known = self
+                        .iter_names()
+                        .fold(0, |acc, (_, f)| acc | f.bits());
+                    let rest = self.bits() & !known;
+                    if rest != 0 {
+                        names.push(format!("{rest:#010x}"));
+                    }
+
+                    names.serialize(s)
+                }
+            }
+
+            impl<'de> Deserialize<'de> for $t {
+                fn deserialize<D: Deserializer<'de>>(
+                    d: D,
+                ) -> Result<Self, D::Error> {
+                    let names = Vec::<String>::deserialize(d)?;
+
+                    let mut out = Self::empty();
+                    for n in names {
+                        if let Some(f) = Self::from_name(&n) {
+                            out |= f;
+                        } else if let Some(hex) = n.strip_prefix("0x") {
+                            let bits = u32::from_str_radix(hex, 16)
+                                .map_err(D::Error::custom)?;
+                            out |= Self::from_bits_retain(bits as _);
+                        } else {
+                            return Err(D::Error::custom(format!(
+                                "unknown flag {n:?}",
+                            )));
+                        }
+                    }
+
+                    Ok(out)
+                }
+            }
+        )*
+    };
+}
+
+flags_serde!(
+    AudioBusy,
+    AudioCaps,
+    AudioFormats,
+    Trigger,
+    HwFeatures,
+    InputPorts,
+    OutputPorts,
+    SwFeatures,
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names() {
+        let f = AudioFormats::AFMT_S16_LE | AudioFormats::AFMT_S32_LE;
+        let json = serde_json::to_string(&f).unwrap();
+        assert_eq!(json, r#"["AFMT_S16_LE","AFMT_S32_LE"]"#);
+        assert_eq!(serde_json::from_str::<AudioFormats>(&json).unwrap(), f);
+
+        let empty = serde_json::to_string(&AudioBusy::empty()).unwrap();
+        assert_eq!(empty, "[]");
+        assert!(serde_json::from_str::<AudioBusy>("[]").unwrap().is_empty());
+    }
+
+    #[test]
+    fn unknown_bits_kept() {
+        /*
+         * The revision level in the low byte of the capabilities has no
+         * name, and neither does a format bit from a newer driver.
+         */
+        let caps = AudioCaps::PCM_CAP_OUTPUT
+            | AudioCaps::PCM_CAP_DEFAULT
+            | AudioCaps::from_bits_retain(0x05);
+        let json = serde_json::to_string(&caps).unwrap();
+        assert_eq!(
+            json,
+            r#"["PCM_CAP_OUTPUT","PCM_CAP_DEFAULT","0x00000005"]"#
+        );
+        assert_eq!(serde_json::from_str::<AudioCaps>(&json).unwrap(), caps);
+
+        let formats = AudioFormats::AFMT_FLOAT
+            | AudioFormats::from_bits_retain(i32::MIN | 0x0100_0000);
+        let json = serde_json::to_string(&formats).unwrap();
+        assert_eq!(json, r#"["AFMT_FLOAT","0x81000000"]"#);
+        assert_eq!(
+            serde_json::from_str::<AudioFormats>(&json).unwrap(),
+            formats
+        );
+    }
+
+    #[test]
+    fn bad_names_rejected() {
+        for json in
+            [r#"["AFMT_BOGUS"]"#, r#"["0xnothex"]"#, r#"["0x100000000"]"#]
+        {
+            assert!(
+                serde_json::from_str::<AudioFormats>(json).is_err(),
+                "{json}"
+            );
+        }
+    }
+}
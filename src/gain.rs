@@ -0,0 +1,362 @@
+use std::time::Duration;
+
+use crate::{
+    pipeline::{Format, Processor},
+    sample::SampleFormat,
+    stream::StreamConfig,
+};
+
+/*
+ * Exponential ramps cannot start from or reach a gain of zero, so they run to
+ * or from this level (-100 dB) instead, and then jump the rest of the way.
+ */
+const EXP_FLOOR: f64 = 1e-5;
+
+/**
+ * How a gain change is applied over time.  Durations are converted to a whole
+ * number of frames at the stream rate, and the ramp advances once per frame,
+ * so that the result does not depend on how audio is split into buffers.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Ramp {
+    /**
+     * Change the gain at the start of the next frame.
+     */
+    Immediate,
+    /**
+     * Change the gain by equal steps of amplitude.
+     */
+    Linear(Duration),
+    /**
+     * Change the gain by equal steps of decibels, which sounds even to the
+     * ear.
+     */
+    Exponential(Duration),
+}
+
+#[derive(Debug, Clone)]
+struct Ramper {
+    current: f64,
+    target: f64,
+    remaining: u64,
+    step: f64,
+    exponential: bool,
+}
+
+impl Ramper {
+    fn new(value: f64) -> Self {
+        Ramper {
+            current: value,
+            target: value,
+            remaining: 0,
+            step: 0.0,
+            exponential: false,
+        }
+    }
+
+    fn start(&mut self, target: f64, ramp: Ramp, rate: u32) {
+        let (dur, exponential) = match ramp {
+            Ramp::Immediate => (Duration::ZERO, false),
+            Ramp::Linear(d) => (d, false),
+            Ramp::Exponential(d) => (d, true),
+        };
+        let frames = (dur.as_secs_f64() * f64::from(rate)).round() as u64;
+
+        self.target = target;
+        self.remaining = frames;
+        self.exponential = exponential;
+
+        if frames == 0 {
+            self.current = target;
+        } else if exponential {
+            self.current = self.current.max(EXP_FLOOR);
+            let ratio = target.max(EXP_FLOOR) / self.current;
+            self.step = ratio.powf(1.0 / frames as f64);
+        } else {
+            self.step = (target - self.current) / frames as f64;
+        }
+    }
+
+    /**
+     * Return the gain for the current frame, and move on to the next.
+     */
+    fn next(&mut self) -> f64 {
+        let g = self.current;
+
+        if self.remaining > 0 {
+            self.remaining -= 1;
+            if self.remaining == 0 {
+                self.current = self.target;
+            } else if self.exponential {
+                self.current *= self.step;
+            } else {
+                self.current += self.step;
+            }
+        }
+
+        g
+    }
+
+    fn is_ramping(&self) -> bool {
+        self.remaining > 0
+    }
+}
+
+/**
+ * A software gain stage, applied to interleaved frames before they are passed
+ * to Dsp::play().  Unlike the hardware volume, the gain of each channel can be
+ * set precisely and changed smoothly, so that volume changes, muting, and
+ * starting or stopping playback do not click.
+ *
+ * Each channel has its own gain, and a separate mute control applies to all
+ * channels on top of that; muting and unmuting leaves the channel gains as
+ * they were.
+ */
+#[derive(Debug, Clone)]
+pub struct Gain {
+    format: SampleFormat,
+    rate: u32,
+    channels: Vec<Ramper>,
+    mute: Ramper,
+}
+
+impl Gain {
+    /**
+     * Create a gain stage at unity gain (0 dB) on every channel.
+     */
+    pub fn new(format: SampleFormat, channels: usize, rate: u32) -> Self {
+        Gain {
+            format,
+            rate,
+            channels: vec![Ramper::new(1.0); channels],
+            mute: Ramper::new(1.0),
+        }
+    }
+
+    /**
+     * Create a gain stage for audio in the format used by a Stream.  Fails
+     * with EINVAL if the format is not one we can convert.
+     */
+    pub fn for_stream(config: &StreamConfig) -> std::io::Result<Self> {
+        let format = SampleFormat::from_audio_format(config.format)
+            .ok_or_else(|| std::io::Error::from_raw_os_error(libc::EINVAL))?;
+
+        Ok(Self::new(format, config.channels as usize, config.rate))
+    }
+
+    pub fn channels(&self) -> usize {
+        self.channels.len()
+    }
+
+    /**
+     * The gain the channel has or is ramping to, in decibels.
+     */
+    pub fn gain_db(&self, channel: usize) -> f64 {
+        gain_to_db(self.channels[channel].target)
+    }
+
+    /**
+     * Change the gain of one channel.  A gain of negative infinity silences
+     * the channel.  Panics if the channel does not exist.
+     */
+    pub fn gain_db_set(&mut self, channel: usize, db: f64, ramp: Ramp) {
+        let rate = self.rate;
+        self.channels[channel].start(db_to_gain(db), ramp, rate);
+    }
+
+    /**
+     * Change the gain of every channel.
+     */
+    pub fn gain_db_set_all(&mut self, db: f64, ramp: Ramp) {
+        let rate = self.rate;
+        for c in &mut self.channels {
+            c.start(db_to_gain(db), ramp, rate);
+        }
+    }
+
+    pub fn mute(&mut self, ramp: Ramp) {
+        self.mute.start(0.0, ramp, self.rate);
+    }
+
+    pub fn unmute(&mut self, ramp: Ramp) {
+        self.mute.start(1.0, ramp, self.rate);
+    }
+
+    /**
+     * Whether the stage is muted, or is ramping towards being muted.
+     */
+    pub fn is_muted(&self) -> bool {
+        self.mute.target == 0.0
+    }
+
+    /**
+     * Whether any gain change is still in progress.
+     */
+    pub fn is_ramping(&self) -> bool {
+        self.mute.is_ramping() || self.channels.iter().any(Ramper::is_ramping)
+    }
+
+    /**
+     * Apply the gain to a buffer of interleaved frames in place.  Any partial
+     * frame at the end of the buffer is left alone.
+     */
+    pub fn process(&mut self, buf: &mut [u8]) {
+        let ss = self.format.size();
+        let fs = ss * self.channels.len();
+        if fs == 0 {
+            return;
+        }
+
+        for frame in buf.chunks_exact_mut(fs) {
+            let m = self.mute.next();
+            for (c, s) in
+                self.channels.iter_mut().zip(frame.chunks_exact_mut(ss))
+            {
+                let g = c.next() * m;
+                if g != 1.0 {
+                    let v = self.format.decode(s);
+                    self.format.encode((f64::from(v) * g) as f32, s);
+                }
+            }
+        }
+    }
+
+    /**
+     * Apply the gain to interleaved floating point frames in place.
+     */
+    pub fn process_f32(&mut self, buf: &mut [f32]) {
+        let nch = self.channels.len();
+        if nch == 0 {
+            return;
+        }
+
+        for frame in buf.chunks_exact_mut(nch) {
+            let m = self.mute.next();
+            for (c, s) in self.channels.iter_mut().zip(frame.iter_mut()) {
+                *s = (f64::from(*s) * c.next() * m) as f32;
+            }
+        }
+    }
+}
+
+impl Processor for Gain {
+    fn format(&self) -> Option<Format> {
+        Some(Format::new(self.channels.len(), self.rate))
+    }
+
+    fn process_block(&mut self, buf: &mut [f32]) {
+        self.process_f32(buf)
+    }
+}
+
+pub fn db_to_gain(db: f64) -> f64 {
+    10f64.powf(db / 20.0)
+}
+
+pub fn gain_to_db(gain: f64) -> f64 {
+    20.0 * gain.log10()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 48000;
+
+    /**
+     * Run "frames" frames of full scale DC through the gain stage, in
+     * buffers of "block" frames, and return the gain applied to each sample.
+     */
+    fn run(g: &mut Gain, frames: usize, block: usize) -> Vec<f32> {
+        let mut out = vec![1.0; frames * g.channels()];
+        for chunk in out.chunks_mut(block * g.channels()) {
+            g.process_f32(chunk);
+        }
+        out
+    }
+
+    #[test]
+    fn linear_ramp_is_sample_accurate() {
+        /*
+         * 10 ms at 48 kHz is 480 frames: the ramp starts on frame 0 and the
+         * target is reached on frame 480, however the audio is split up.
+         */
+        for block in [1, 100, 480, 1000] {
+            let mut g = Gain::new(SampleFormat::Float, 2, RATE);
+            g.gain_db_set_all(-6.0, Ramp::Linear(Duration::from_millis(10)));
+            let target = db_to_gain(-6.0);
+
+            let out = run(&mut g, 1000, block);
+            for (i, frame) in out.chunks(2).enumerate() {
+                let expect = if i < 480 {
+                    1.0 + (target - 1.0) * i as f64 / 480.0
+                } else {
+                    target
+                };
+                assert!((f64::from(frame[0]) - expect).abs() < 1e-6, "{i}");
+                assert_eq!(frame[0], frame[1]);
+            }
+            assert!(f64::from(out[2 * 479]) - target > 1e-4);
+            assert!(!g.is_ramping());
+        }
+    }
+
+    #[test]
+    fn exponential_ramp_is_even_in_db() {
+        let mut g = Gain::new(SampleFormat::Float, 1, RATE);
+        g.gain_db_set(0, -20.0, Ramp::Exponential(Duration::from_millis(1)));
+
+        let out = run(&mut g, 100, 7);
+        for (i, v) in out.iter().enumerate() {
+            let expect = -20.0 * (i.min(48) as f64) / 48.0;
+            let db = gain_to_db(f64::from(*v));
+            assert!((db - expect).abs() < 1e-3, "{i}: {db}");
+        }
+        assert_eq!(g.gain_db(0), -20.0);
+    }
+
+    #[test]
+    fn mute_without_a_step() {
+        let mut g = Gain::new(SampleFormat::Float, 2, RATE);
+        g.gain_db_set_all(-3.0, Ramp::Immediate);
+        g.mute(Ramp::Linear(Duration::from_millis(10)));
+        assert!(g.is_muted());
+
+        /*
+         * The level falls by the same small step each frame, and is exactly
+         * silent from frame 480 on.
+         */
+        let level = db_to_gain(-3.0);
+        let out = run(&mut g, 1000, 64);
+        assert!((f64::from(out[0]) - level).abs() < 1e-6);
+        for (i, w) in out.chunks(2).collect::<Vec<_>>().windows(2).enumerate() {
+            let step = f64::from(w[0][0] - w[1][0]);
+            if i < 480 {
+                assert!((step - level / 480.0).abs() < 1e-6, "{i}: {step}");
+            } else {
+                assert_eq!(step, 0.0);
+            }
+        }
+        assert!(out[2 * 480..].iter().all(|&v| v == 0.0));
+
+        /*
+         * Unmuting restores the channel gain, which muting left alone.
+         */
+        g.unmute(Ramp::Immediate);
+        assert!(!g.is_muted());
+        assert_eq!(g.gain_db(0), -3.0);
+        let out = run(&mut g, 4, 4);
+        assert!(out.iter().all(|&v| (f64::from(v) - level).abs() < 1e-6));
+    }
+
+    #[test]
+    fn integer_samples() {
+        let mut g = Gain::new(SampleFormat::S16LE, 1, RATE);
+        g.gain_db_set(0, gain_to_db(0.5), Ramp::Immediate);
+
+        let mut buf: Vec<u8> =
+            [1000i16, -2000].iter().flat_map(|s| s.to_le_bytes()).collect();
+        g.process(&mut buf);
+        assert_eq!(buf, [500i16, -1000].map(i16::to_le_bytes).concat());
+    }
+}
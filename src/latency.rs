@@ -0,0 +1,389 @@
+use std::time::Duration;
+
+use crate::{sample::SampleFormat, sys, Dsp, StreamConfig};
+
+/*
+ * How long to wait for recorded data before deciding the input has stalled.
+ */
+const READ_TIMEOUT: Duration = Duration::from_secs(2);
+
+/*
+ * The correlation peak must stand this far above the average correlation
+ * magnitude for a run to be considered a detection rather than noise.
+ */
+const MIN_CONFIDENCE: f32 = 8.0;
+
+/**
+ * The test signal played during a latency measurement.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stimulus {
+    /**
+     * A maximum length sequence of 2^order - 1 samples.
+     */
+    Mls { order: u32 },
+    /**
+     * An exponential sine sweep from f0 to f1 Hz.
+     */
+    Chirp { duration: Duration, f0: f32, f1: f32 },
+}
+
+impl Stimulus {
+    /**
+     * Generate the stimulus at the given sample rate, with a peak level of
+     * 1.0.
+     */
+    pub fn generate(&self, rate: u32) -> Vec<f32> {
+        match *self {
+            Stimulus::Mls { order } => mls(order),
+            Stimulus::Chirp { duration, f0, f1 } => {
+                chirp(rate, duration, f0, f1)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LatencyConfig {
+    pub stream: StreamConfig,
+    pub stimulus: Stimulus,
+    /**
+     * The stimulus is scaled by this factor before it is played.
+     */
+    pub level: f32,
+    pub runs: usize,
+    /**
+     * The largest round-trip latency we will search for.  Recording continues
+     * for this long after the stimulus has been played.
+     */
+    pub max_latency: Duration,
+}
+
+impl Default for LatencyConfig {
+    fn default() -> Self {
+        LatencyConfig {
+            stream: StreamConfig {
+                format: sys::AudioFormats::AFMT_S16_LE,
+                rate: 48000,
+                channels: 2,
+            },
+            stimulus: Stimulus::Mls { order: 12 },
+            level: 0.5,
+            runs: 5,
+            max_latency: Duration::from_millis(500),
+        }
+    }
+}
+
+/**
+ * The outcome of a single run.  The latency is None if no correlation peak
+ * could be found; e.g., because there is no loopback connection.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LatencyRun {
+    pub frames: Option<usize>,
+    pub confidence: f32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LatencyReport {
+    pub rate: u32,
+    pub runs: Vec<LatencyRun>,
+}
+
+impl LatencyReport {
+    fn detected(&self) -> impl Iterator<Item = f64> + '_ {
+        self.runs
+            .iter()
+            .filter_map(|r| r.frames)
+            .map(|f| f as f64 * 1000.0 / self.rate as f64)
+    }
+
+    /**
+     * The number of runs in which the stimulus was detected.
+     */
+    pub fn detections(&self) -> usize {
+        self.detected().count()
+    }
+
+    pub fn mean_ms(&self) -> Option<f64> {
+        let n = self.detections();
+        (n > 0).then(|| self.detected().sum::<f64>() / n as f64)
+    }
+
+    pub fn min_ms(&self) -> Option<f64> {
+        self.detected().reduce(f64::min)
+    }
+
+    pub fn max_ms(&self) -> Option<f64> {
+        self.detected().reduce(f64::max)
+    }
+
+    /**
+     * The standard deviation of the detected latencies.
+     */
+    pub fn jitter_ms(&self) -> Option<f64> {
+        let mean = self.mean_ms()?;
+        let n = self.detections() as f64;
+        let var = self.detected().map(|v| (v - mean).powi(2)).sum::<f64>() / n;
+        Some(var.sqrt())
+    }
+}
+
+/**
+ * Measure the round-trip latency from "output" to "input" by playing the
+ * stimulus and locating it in the recording by cross-correlation.  If "input"
+ * is None, "output" must have been opened with Direction::Duplex and will be
+ * used for both directions; its input and output engines are started at the
+ * same instant.  When a separate input device is used, the two devices are
+ * started one after the other, so the result includes the small delay
+ * between those two calls.
+ */
+pub fn measure(
+    output: &Dsp,
+    input: Option<&Dsp>,
+    config: &LatencyConfig,
+) -> std::io::Result<LatencyReport> {
+    let einval = || std::io::Error::from_raw_os_error(libc::EINVAL);
+
+    let sc = &config.stream;
+    let fmt = SampleFormat::from_audio_format(sc.format).ok_or_else(einval)?;
+    let channels: usize = sc.channels.try_into().map_err(|_| einval())?;
+    if channels == 0 || config.runs == 0 {
+        return Err(einval());
+    }
+
+    if input.is_none() {
+        output.duplex_set()?;
+    }
+    for dsp in std::iter::once(output).chain(input) {
+        dsp.format_set(sc.format)?;
+        dsp.channels_set(sc.channels)?;
+        dsp.speed_set(sc.rate)?;
+    }
+
+    let stimulus: Vec<f32> = config
+        .stimulus
+        .generate(sc.rate)
+        .into_iter()
+        .map(|v| v * config.level)
+        .collect();
+    let max_lag = (config.max_latency.as_secs_f64() * sc.rate as f64) as usize;
+
+    /*
+     * Play the stimulus on every channel, followed by enough silence to cover
+     * the longest latency we are looking for.
+     */
+    let frame_size = fmt.size() * channels;
+    let total = stimulus.len() + max_lag;
+    let mut play = vec![0u8; total * frame_size];
+    for (frame, v) in play.chunks_exact_mut(frame_size).zip(&stimulus) {
+        for s in frame.chunks_exact_mut(fmt.size()) {
+            fmt.encode(*v, s);
+        }
+    }
+    if fmt.silence() != 0 {
+        play[stimulus.len() * frame_size..].fill(fmt.silence());
+    }
+
+    let mut runs = Vec::with_capacity(config.runs);
+    for _ in 0..config.runs {
+        let rec = record_while_playing(
+            output,
+            input.unwrap_or(output),
+            fmt,
+            channels,
+            &play,
+        )?;
+        runs.push(locate(&stimulus, &rec, max_lag));
+    }
+
+    Ok(LatencyReport { rate: sc.rate, runs })
+}
+
+/**
+ * Play the whole of "play" while recording the same number of frames, and
+ * return the first channel of the recording.
+ */
+pub(crate) fn record_while_playing(
+    output: &Dsp,
+    input: &Dsp,
+    fmt: SampleFormat,
+    channels: usize,
+    play: &[u8],
+) -> std::io::Result<Vec<f32>> {
+    let frame_size = fmt.size() * channels;
+    let total = play.len() / frame_size;
+    let same = std::ptr::eq(output, input);
+
+    /*
+     * Reset both directions and prevent the engines from starting
+     * automatically when we queue the first part of the stimulus.
+     */
+    output.halt()?;
+    output.trigger_set(sys::Trigger::empty())?;
+    if !same {
+        input.halt()?;
+        input.trigger_set(sys::Trigger::empty())?;
+    }
+
+    let mut written = queue(output, play, 0)?;
+
+    if same {
+        output.trigger_set(
+            sys::Trigger::PCM_ENABLE_INPUT | sys::Trigger::PCM_ENABLE_OUTPUT,
+        )?;
+    } else {
+        input.trigger_set(sys::Trigger::PCM_ENABLE_INPUT)?;
+        output.trigger_set(sys::Trigger::PCM_ENABLE_OUTPUT)?;
+    }
+
+    let mut rec = Vec::with_capacity(total);
+    let mut buf = Vec::new();
+    while rec.len() < total {
+        written += queue(output, play, written)?;
+
+        if !input.wait_readable(Some(READ_TIMEOUT))? {
+            return Err(std::io::Error::from(std::io::ErrorKind::TimedOut));
+        }
+
+        let avail = usize::try_from(input.space_input()?.bytes).unwrap_or(0);
+        let want = (avail / frame_size).min(total - rec.len()).max(1);
+        buf.resize(want * frame_size, 0);
+        let n = input.record(&mut buf)?;
+
+        rec.extend(
+            buf[..n - n % frame_size]
+                .chunks_exact(frame_size)
+                .map(|frame| fmt.decode(frame)),
+        );
+    }
+
+    output.halt()?;
+    if !same {
+        input.halt()?;
+    }
+
+    Ok(rec)
+}
+
+/**
+ * Write as much of "play", starting at byte offset "from", as will fit in the
+ * play buffer without blocking.  Returns the number of bytes written.
+ */
+fn queue(dsp: &Dsp, play: &[u8], from: usize) -> std::io::Result<usize> {
+    let space = usize::try_from(dsp.space_output()?.bytes).unwrap_or(0);
+    let n = space.min(play.len() - from);
+    if n > 0 {
+        dsp.play(&play[from..from + n])?;
+    }
+    Ok(n)
+}
+
+/**
+ * Find the lag, up to "max_lag" samples, at which the stimulus best matches
+ * the recording.
+ */
+fn locate(stimulus: &[f32], rec: &[f32], max_lag: usize) -> LatencyRun {
+    let corr = cross_correlate(stimulus, rec, max_lag);
+
+    let mut best = 0;
+    let mut sum = 0.0f64;
+    for (lag, c) in corr.iter().enumerate() {
+        sum += c.abs() as f64;
+        if c.abs() > corr[best].abs() {
+            best = lag;
+        }
+    }
+
+    let mean = (sum / corr.len().max(1) as f64) as f32;
+    let peak = corr.get(best).map(|c| c.abs()).unwrap_or(0.0);
+    let confidence = if mean > 0.0 { peak / mean } else { 0.0 };
+
+    LatencyRun {
+        frames: (confidence >= MIN_CONFIDENCE).then_some(best),
+        confidence,
+    }
+}
+
+/**
+ * Compute the correlation of "reference" against "signal" at each lag from 0
+ * to "max_lag" inclusive.  Lags at which the reference would extend past the
+ * end of the signal are correlated against the available overlap only.
+ */
+pub fn cross_correlate(
+    reference: &[f32],
+    signal: &[f32],
+    max_lag: usize,
+) -> Vec<f32> {
+    (0..=max_lag.min(signal.len()))
+        .map(|lag| {
+            signal[lag..].iter().zip(reference).map(|(s, r)| s * r).sum::<f32>()
+        })
+        .collect()
+}
+
+/**
+ * Generate a maximum length sequence of 2^order - 1 samples, each either 1.0
+ * or -1.0, using a Fibonacci linear feedback shift register.  Orders from 2
+ * to 20 are supported; other values are clamped into that range.
+ */
+pub fn mls(order: u32) -> Vec<f32> {
+    /*
+     * Feedback taps for a primitive polynomial of each order.  Bit i of the
+     * mask corresponds to the x^i term; the x^order term is implicit.
+     */
+    const TAPS: [u32; 21] = [
+        0, 0, 0x3, 0x3, 0x3, 0x5, 0x3, 0x3, 0x1d, 0x11, 0x9, 0x5, 0x53, 0x1b,
+        0x443, 0x3, 0x100b, 0x9, 0x81, 0x27, 0x9,
+    ];
+
+    let order = order.clamp(2, 20);
+    let taps = TAPS[order as usize];
+    let len = (1usize << order) - 1;
+
+    let mut reg: u32 = 1;
+    (0..len)
+        .map(|_| {
+            let bit = (reg & taps).count_ones() & 1;
+            let out = if reg & 1 != 0 { 1.0 } else { -1.0 };
+            reg = (reg >> 1) | (bit << (order - 1));
+            out
+        })
+        .collect()
+}
+
+/**
+ * Generate an exponential sine sweep from "f0" to "f1" Hz, with short raised
+ * cosine fades at each end to avoid clicks.
+ */
+pub fn chirp(rate: u32, duration: Duration, f0: f32, f1: f32) -> Vec<f32> {
+    let rate = rate as f64;
+    let n = (duration.as_secs_f64() * rate) as usize;
+    let t1 = duration.as_secs_f64();
+    let (f0, f1) = (f0.max(1.0) as f64, f1.max(1.0) as f64);
+    let k = (f1 / f0).ln();
+    let fade = (n / 20).max(1);
+
+    (0..n)
+        .map(|i| {
+            let t = i as f64 / rate;
+            let phase = if k.abs() < 1e-9 {
+                2.0 * std::f64::consts::PI * f0 * t
+            } else {
+                2.0 * std::f64::consts::PI * f0 * t1 / k
+                    * ((t / t1 * k).exp() - 1.0)
+            };
+
+            let edge = i.min(n - 1 - i);
+            let gain = if edge < fade {
+                let x = std::f64::consts::PI * edge as f64 / fade as f64;
+                0.5 - 0.5 * x.cos()
+            } else {
+                1.0
+            };
+
+            (phase.sin() * gain) as f32
+        })
+        .collect()
+}
@@ -1,11 +1,52 @@
-use std::{ffi::CStr, fs::File, mem::MaybeUninit, os::fd::AsRawFd as _};
+use std::{
+    fs::File,
+    mem::MaybeUninit,
+    os::fd::AsRawFd as _,
+    time::{Duration, Instant},
+};
 
 pub mod mixer;
+pub mod monitor;
 pub mod dsp;
+#[cfg(feature = "serde")]
+mod flags_serde;
+pub mod dynamics;
+pub mod eq;
+pub mod gain;
+pub mod generator;
+pub mod latency;
+pub mod meter;
+pub mod pipeline;
+pub mod query;
+pub mod resample;
+pub mod ring;
+pub mod sample;
+pub mod softmix;
+pub mod spectrum;
+pub mod stream;
+pub mod sunaudio;
 pub mod sys;
+pub mod volume;
+#[cfg(feature = "tokio")]
+pub mod async_dsp;
 
 pub use mixer::Mixer;
-pub use dsp::Dsp;
+pub use monitor::{DeviceEvent, DeviceMonitor};
+pub use dsp::{Direction, Dsp};
+pub use dynamics::{Compressor, Limiter};
+pub use eq::{Filter, FilterChain};
+pub use gain::{Gain, Ramp};
+pub use meter::Meter;
+pub use pipeline::{Pipeline, Processor, Sink, Source};
+pub use query::DeviceQuery;
+pub use ring::{ring_buffer, RingConsumer, RingProducer};
+pub use sample::SampleFormat;
+pub use softmix::{MixerSource, SoftMixer};
+pub use stream::{Stream, StreamConfig};
+pub use sunaudio::SunAudio;
+pub use volume::Volume;
+#[cfg(feature = "tokio")]
+pub use async_dsp::AsyncDsp;
 
 fn basic_ioctl_inout<T>(f: &File, cmd: i32, mut buf: T) -> std::io::Result<T> {
     let fd = f.as_raw_fd();
@@ -39,9 +80,73 @@ fn basic_ioctl_noarg(f: &File, cmd: i32) -> std::io::Result<()> {
     Ok(())
 }
 
-fn c_chars_to_string(input: &[libc::c_char]) -> Option<String> {
-    let input = unsafe { std::mem::transmute(input) };
-    let cs = CStr::from_bytes_until_nul(input).ok()?;
-    let s = cs.to_str().ok()?;
-    Some(s.to_string())
+/**
+ * Wait for any of the requested poll(2) events on a single file.  Returns true
+ * if the file became ready (or reported an error or hangup, which the next
+ * read or write will surface) and false if the timeout expired first.  A
+ * timeout of None means wait forever.
+ */
+fn poll_one(
+    f: &File,
+    events: libc::c_short,
+    timeout: Option<Duration>,
+) -> std::io::Result<bool> {
+    let deadline = timeout.map(|t| Instant::now() + t);
+
+    loop {
+        let ms: libc::c_int = match deadline {
+            None => -1,
+            Some(d) => {
+                /*
+                 * Round up to the next millisecond so that a short but
+                 * non-zero timeout does not degenerate into a busy poll.
+                 */
+                let rem = d.saturating_duration_since(Instant::now());
+                let ms = rem.as_micros().div_ceil(1000);
+                ms.min(libc::c_int::MAX as u128) as libc::c_int
+            }
+        };
+
+        let mut pfd = libc::pollfd { fd: f.as_raw_fd(), events, revents: 0 };
+        let r = unsafe { libc::poll(&mut pfd, 1, ms) };
+        if r < 0 {
+            let e = std::io::Error::last_os_error();
+            if e.kind() == std::io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e);
+        }
+
+        if r == 0 {
+            return Ok(false);
+        }
+
+        if pfd.revents & libc::POLLNVAL != 0 {
+            return Err(std::io::Error::from_raw_os_error(libc::EBADF));
+        }
+
+        return Ok(true);
+    }
+}
+
+/**
+ * Decode a fixed-size string from a driver structure.  The string ends at the
+ * first NUL, or at the end of the array if there is none, and any invalid
+ * UTF-8 is replaced with U+FFFD.
+ */
+fn c_chars_to_string(input: &[libc::c_char]) -> String {
+    String::from_utf8_lossy(sys::c_chars_bytes(input)).into_owned()
+}
+
+/**
+ * Convert an integer reported by the driver that should never be negative.
+ */
+fn c_int_to_u32(v: libc::c_int) -> std::io::Result<u32> {
+    u32::try_from(v)
+        .map_err(|_| std::io::Error::from_raw_os_error(libc::EINVAL))
+}
+
+fn u32_to_c_int(v: u32) -> std::io::Result<libc::c_int> {
+    libc::c_int::try_from(v)
+        .map_err(|_| std::io::Error::from_raw_os_error(libc::EINVAL))
 }
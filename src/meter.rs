@@ -0,0 +1,659 @@
+/*
+ * Level metering for interleaved audio: sample peak, RMS, clip counts and
+ * true peak per channel, along with loudness as defined by ITU-R BS.1770 and
+ * EBU R128.  All storage is allocated up front, so that frames may be
+ * metered from within a real-time data callback.
+ */
+
+use crate::{
+    pipeline::{Format, Processor},
+    sample::SampleFormat,
+    stream::StreamConfig,
+};
+
+/*
+ * True peak is estimated by 4x oversampling with a windowed-sinc polyphase
+ * interpolator of 12 taps per phase, as BS.1770 recommends.
+ */
+const TP_FACTOR: usize = 4;
+const TP_TAPS: usize = 12;
+
+/*
+ * Loudness is measured over blocks made of 100 ms sub-blocks: 4 for a
+ * momentary (400 ms) block, and 30 for a short-term (3 s) block.
+ */
+const SUBBLOCK_SECS: f64 = 0.1;
+const MOMENTARY_SUBBLOCKS: usize = 4;
+const SHORT_TERM_SUBBLOCKS: usize = 30;
+
+/*
+ * The integrated loudness gates are computed from a histogram of the loudness
+ * of each 400 ms block, with bins 0.1 LU wide from the absolute gate at -70
+ * LUFS up to +30 LUFS.
+ */
+const ABSOLUTE_GATE: f64 = -70.0;
+const RELATIVE_GATE: f64 = -10.0;
+const HIST_MAX: f64 = 30.0;
+const HIST_STEP: f64 = 0.1;
+const HIST_BINS: usize = ((HIST_MAX - ABSOLUTE_GATE) / HIST_STEP) as usize;
+
+/**
+ * The levels seen on one channel since the meter was created or the levels
+ * were last reset.  Levels are linear, relative to full scale.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChannelLevels {
+    pub peak: f32,
+    pub rms: f32,
+    pub true_peak: f32,
+    /**
+     * The number of samples at or beyond the largest value the sample format
+     * can represent.
+     */
+    pub clips: u64,
+}
+
+impl ChannelLevels {
+    pub fn peak_db(&self) -> f64 {
+        to_db(self.peak)
+    }
+
+    pub fn rms_db(&self) -> f64 {
+        to_db(self.rms)
+    }
+
+    /**
+     * True peak in dBTP.
+     */
+    pub fn true_peak_db(&self) -> f64 {
+        to_db(self.true_peak)
+    }
+}
+
+/**
+ * Loudness in LUFS.  Each measurement is negative infinity until enough audio
+ * has been seen to make it, or if the audio has been silent.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Loudness {
+    /**
+     * Over the last 400 ms.
+     */
+    pub momentary: f64,
+    /**
+     * Over the last 3 s.
+     */
+    pub short_term: f64,
+    /**
+     * Gated loudness over everything since the loudness was last reset.
+     */
+    pub integrated: f64,
+}
+
+/**
+ * A biquad filter section (direct form I), used for K-weighting.
+ */
+#[derive(Debug, Clone, Copy, Default)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    x: [f64; 2],
+    y: [f64; 2],
+}
+
+impl Biquad {
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.b[1] * self.x[0] + self.b[2] * self.x[1]
+            - self.a[0] * self.y[0]
+            - self.a[1] * self.y[1];
+        self.x = [x, self.x[0]];
+        self.y = [y, self.y[0]];
+        y
+    }
+}
+
+/**
+ * The two K-weighting filter stages from BS.1770, a high shelf modelling the
+ * acoustic effect of the head and a high pass, with coefficients derived for
+ * the given rate rather than only the 48 kHz values in the standard.
+ */
+fn k_weighting(rate: u32) -> [Biquad; 2] {
+    let fs = f64::from(rate);
+
+    let shelf = {
+        let f0 = 1681.974450955533;
+        let g = 3.999843853973347;
+        let q = 0.7071752369554196;
+
+        let k = (std::f64::consts::PI * f0 / fs).tan();
+        let vh = 10f64.powf(g / 20.0);
+        let vb = vh.powf(0.4996667741545416);
+        let a0 = 1.0 + k / q + k * k;
+
+        Biquad {
+            b: [
+                (vh + vb * k / q + k * k) / a0,
+                2.0 * (k * k - vh) / a0,
+                (vh - vb * k / q + k * k) / a0,
+            ],
+            a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+            ..Default::default()
+        }
+    };
+
+    let highpass = {
+        let f0 = 38.13547087602444;
+        let q = 0.5003270373238773;
+
+        let k = (std::f64::consts::PI * f0 / fs).tan();
+        let a0 = 1.0 + k / q + k * k;
+
+        Biquad {
+            b: [1.0, -2.0, 1.0],
+            a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+            ..Default::default()
+        }
+    };
+
+    [shelf, highpass]
+}
+
+#[derive(Debug, Clone)]
+struct Channel {
+    peak: f32,
+    true_peak: f32,
+    sum_squares: f64,
+    clips: u64,
+
+    /*
+     * The most recent samples, newest first, for the true peak interpolator.
+     */
+    history: [f32; TP_TAPS],
+
+    weight: f64,
+    filters: [Biquad; 2],
+    /*
+     * The sum of squared K-weighted samples in the current sub-block.
+     */
+    energy: f64,
+}
+
+/**
+ * Meters interleaved frames in a given format.  Feed it audio with process()
+ * or process_f32(), and read the results with levels() and loudness().
+ *
+ * For loudness, each channel is weighted by 1.0 unless changed with
+ * channel_weight_set().  BS.1770 gives the surround channels of a 5.1 layout a
+ * weight of 1.41, and excludes the LFE channel (a weight of 0).
+ */
+#[derive(Debug, Clone)]
+pub struct Meter {
+    format: SampleFormat,
+    rate: u32,
+    clip_level: f32,
+    frames: u64,
+    channels: Vec<Channel>,
+    phases: [[f32; TP_TAPS]; TP_FACTOR],
+
+    subblock_len: usize,
+    subblock_pos: usize,
+    /*
+     * The weighted mean square of each recent sub-block, as a ring.
+     */
+    subblocks: [f64; SHORT_TERM_SUBBLOCKS],
+    subblocks_next: usize,
+    subblocks_filled: usize,
+
+    hist_count: Vec<u64>,
+    hist_energy: Vec<f64>,
+}
+
+impl Meter {
+    pub fn new(format: SampleFormat, channels: usize, rate: u32) -> Self {
+        /*
+         * The largest value the format can represent may be a little less
+         * than 1.0 (e.g., 32767/32768), so find it by a round trip.
+         */
+        let mut b = [0u8; 4];
+        format.encode(1.0, &mut b);
+        let clip_level = format.decode(&b);
+
+        let filters = k_weighting(rate);
+        let channel = Channel {
+            peak: 0.0,
+            true_peak: 0.0,
+            sum_squares: 0.0,
+            clips: 0,
+            history: [0.0; TP_TAPS],
+            weight: 1.0,
+            filters,
+            energy: 0.0,
+        };
+
+        Meter {
+            format,
+            rate,
+            clip_level,
+            frames: 0,
+            channels: vec![channel; channels],
+            phases: interpolator(),
+            subblock_len: ((f64::from(rate) * SUBBLOCK_SECS).round() as usize)
+                .max(1),
+            subblock_pos: 0,
+            subblocks: [0.0; SHORT_TERM_SUBBLOCKS],
+            subblocks_next: 0,
+            subblocks_filled: 0,
+            hist_count: vec![0; HIST_BINS],
+            hist_energy: vec![0.0; HIST_BINS],
+        }
+    }
+
+    /**
+     * Create a meter for audio in the format used by a Stream.  Fails with
+     * EINVAL if the format is not one we can convert.
+     */
+    pub fn for_stream(config: &StreamConfig) -> std::io::Result<Self> {
+        let format = SampleFormat::from_audio_format(config.format)
+            .ok_or_else(|| std::io::Error::from_raw_os_error(libc::EINVAL))?;
+
+        Ok(Self::new(format, config.channels as usize, config.rate))
+    }
+
+    pub fn channels(&self) -> usize {
+        self.channels.len()
+    }
+
+    /**
+     * Set the weight of a channel in the loudness measurement.  Panics if the
+     * channel does not exist.
+     */
+    pub fn channel_weight_set(&mut self, channel: usize, weight: f64) {
+        self.channels[channel].weight = weight;
+    }
+
+    /**
+     * Meter a buffer of interleaved frames.  Any partial frame at the end of
+     * the buffer is ignored.
+     */
+    pub fn process(&mut self, buf: &[u8]) {
+        let ss = self.format.size();
+        let fs = ss * self.channels.len();
+        if fs == 0 {
+            return;
+        }
+
+        for frame in buf.chunks_exact(fs) {
+            for (c, s) in frame.chunks_exact(ss).enumerate() {
+                let v = self.format.decode(s);
+                self.sample(c, v);
+            }
+            self.end_frame();
+        }
+    }
+
+    /**
+     * Meter a buffer of interleaved floating point frames.
+     */
+    pub fn process_f32(&mut self, buf: &[f32]) {
+        let nch = self.channels.len();
+        if nch == 0 {
+            return;
+        }
+
+        for frame in buf.chunks_exact(nch) {
+            for (c, &v) in frame.iter().enumerate() {
+                self.sample(c, v);
+            }
+            self.end_frame();
+        }
+    }
+
+    fn sample(&mut self, c: usize, v: f32) {
+        let phases = &self.phases;
+        let ch = &mut self.channels[c];
+
+        let a = v.abs();
+        ch.peak = ch.peak.max(a);
+        ch.sum_squares += f64::from(v) * f64::from(v);
+        if a >= self.clip_level {
+            ch.clips += 1;
+        }
+
+        ch.history.copy_within(..TP_TAPS - 1, 1);
+        ch.history[0] = v;
+        let mut tp = a;
+        for taps in phases {
+            let y: f32 =
+                taps.iter().zip(ch.history.iter()).map(|(h, x)| h * x).sum();
+            tp = tp.max(y.abs());
+        }
+        ch.true_peak = ch.true_peak.max(tp);
+
+        let k = ch.filters.iter_mut().fold(f64::from(v), |x, f| f.process(x));
+        ch.energy += k * k;
+    }
+
+    fn end_frame(&mut self) {
+        self.frames += 1;
+        self.subblock_pos += 1;
+        if self.subblock_pos < self.subblock_len {
+            return;
+        }
+        self.subblock_pos = 0;
+
+        let n = self.subblock_len as f64;
+        let mut ms = 0.0;
+        for ch in &mut self.channels {
+            ms += ch.weight * ch.energy / n;
+            ch.energy = 0.0;
+        }
+
+        self.subblocks[self.subblocks_next] = ms;
+        self.subblocks_next = (self.subblocks_next + 1) % SHORT_TERM_SUBBLOCKS;
+        self.subblocks_filled =
+            (self.subblocks_filled + 1).min(SHORT_TERM_SUBBLOCKS);
+
+        /*
+         * Each new sub-block completes a 400 ms gating block that overlaps
+         * the previous one by 75%.
+         */
+        if let Some(e) = self.recent_energy(MOMENTARY_SUBBLOCKS) {
+            let l = energy_to_lufs(e);
+            if l >= ABSOLUTE_GATE {
+                let bin = (((l - ABSOLUTE_GATE) / HIST_STEP) as usize)
+                    .min(HIST_BINS - 1);
+                self.hist_count[bin] += 1;
+                self.hist_energy[bin] += e;
+            }
+        }
+    }
+
+    /**
+     * The mean of the last "n" sub-block energies, if there are that many.
+     */
+    fn recent_energy(&self, n: usize) -> Option<f64> {
+        if self.subblocks_filled < n {
+            return None;
+        }
+
+        let sum: f64 = (1..=n)
+            .map(|i| {
+                let j = (self.subblocks_next + SHORT_TERM_SUBBLOCKS - i)
+                    % SHORT_TERM_SUBBLOCKS;
+                self.subblocks[j]
+            })
+            .sum();
+        Some(sum / n as f64)
+    }
+
+    /**
+     * Peak, RMS, true peak and clip count for one channel.  Panics if the
+     * channel does not exist.
+     */
+    pub fn levels(&self, channel: usize) -> ChannelLevels {
+        let ch = &self.channels[channel];
+        let rms = if self.frames == 0 {
+            0.0
+        } else {
+            (ch.sum_squares / self.frames as f64).sqrt() as f32
+        };
+
+        ChannelLevels {
+            peak: ch.peak,
+            rms,
+            true_peak: ch.true_peak,
+            clips: ch.clips,
+        }
+    }
+
+    /**
+     * Start measuring peak, RMS, true peak and clips afresh; e.g., after
+     * each update of a level display.  Loudness is not affected.
+     */
+    pub fn reset_levels(&mut self) {
+        self.frames = 0;
+        for ch in &mut self.channels {
+            ch.peak = 0.0;
+            ch.true_peak = 0.0;
+            ch.sum_squares = 0.0;
+            ch.clips = 0;
+        }
+    }
+
+    pub fn loudness(&self) -> Loudness {
+        let lufs = |n| {
+            self.recent_energy(n)
+                .map(energy_to_lufs)
+                .unwrap_or(f64::NEG_INFINITY)
+        };
+
+        Loudness {
+            momentary: lufs(MOMENTARY_SUBBLOCKS),
+            short_term: lufs(SHORT_TERM_SUBBLOCKS),
+            integrated: self.integrated(),
+        }
+    }
+
+    /**
+     * Apply the two gates from BS.1770: blocks below the absolute gate never
+     * made it into the histogram, and blocks more than 10 LU below the mean
+     * of those that did are discarded too.
+     */
+    fn integrated(&self) -> f64 {
+        let gated_mean = |from: usize| {
+            let count: u64 = self.hist_count[from..].iter().sum();
+            let energy: f64 = self.hist_energy[from..].iter().sum();
+            (count > 0).then(|| energy / count as f64)
+        };
+
+        let Some(mean) = gated_mean(0) else {
+            return f64::NEG_INFINITY;
+        };
+
+        let threshold = energy_to_lufs(mean) + RELATIVE_GATE;
+        let from = ((threshold - ABSOLUTE_GATE) / HIST_STEP).max(0.0) as usize;
+        gated_mean(from.min(HIST_BINS))
+            .map(energy_to_lufs)
+            .unwrap_or(f64::NEG_INFINITY)
+    }
+
+    /**
+     * Start measuring loudness afresh, as at the start of a new programme.
+     */
+    pub fn reset_loudness(&mut self) {
+        self.subblock_pos = 0;
+        self.subblocks_next = 0;
+        self.subblocks_filled = 0;
+        self.hist_count.fill(0);
+        self.hist_energy.fill(0.0);
+        for ch in &mut self.channels {
+            ch.energy = 0.0;
+        }
+    }
+}
+
+impl Processor for Meter {
+    fn format(&self) -> Option<Format> {
+        Some(Format::new(self.channels.len(), self.rate))
+    }
+
+    fn process_block(&mut self, buf: &mut [f32]) {
+        self.process_f32(buf)
+    }
+}
+
+/**
+ * Split a Hann-windowed sinc low pass at the original Nyquist frequency into
+ * one set of taps for each phase of the oversampled output.
+ */
+fn interpolator() -> [[f32; TP_TAPS]; TP_FACTOR] {
+    let len = TP_FACTOR * TP_TAPS;
+    let centre = (len - 1) as f64 / 2.0;
+
+    let mut phases = [[0.0; TP_TAPS]; TP_FACTOR];
+    for i in 0..len {
+        let x = (i as f64 - centre) / TP_FACTOR as f64;
+        let sinc = if x == 0.0 {
+            1.0
+        } else {
+            (std::f64::consts::PI * x).sin() / (std::f64::consts::PI * x)
+        };
+        let w = 0.5
+            - 0.5
+                * (2.0 * std::f64::consts::PI * (i as f64 + 0.5) / len as f64)
+                    .cos();
+        phases[i % TP_FACTOR][i / TP_FACTOR] = (sinc * w) as f32;
+    }
+    phases
+}
+
+fn energy_to_lufs(e: f64) -> f64 {
+    -0.691 + 10.0 * e.log10()
+}
+
+fn to_db(v: f32) -> f64 {
+    20.0 * f64::from(v).log10()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 48000;
+
+    /**
+     * "secs" of a sine at "db" dBFS, on every one of "channels" channels.
+     */
+    fn sine(
+        freq: f64,
+        db: f64,
+        phase: f64,
+        secs: f64,
+        channels: usize,
+    ) -> Vec<f32> {
+        let amplitude = 10f64.powf(db / 20.0);
+        let frames = (secs * f64::from(RATE)) as usize;
+        (0..frames)
+            .flat_map(|i| {
+                let t = i as f64 / f64::from(RATE);
+                let v = amplitude
+                    * (std::f64::consts::TAU * freq * t + phase).sin();
+                std::iter::repeat_n(v as f32, channels)
+            })
+            .collect()
+    }
+
+    fn meter(channels: usize) -> Meter {
+        Meter::new(SampleFormat::Float, channels, RATE)
+    }
+
+    #[test]
+    fn k_weighting_48k() {
+        /*
+         * The coefficients given in BS.1770 for 48 kHz.
+         */
+        let [shelf, highpass] = k_weighting(48000);
+        let close = |a: &[f64], b: &[f64]| {
+            a.iter().zip(b).all(|(x, y)| (x - y).abs() < 1e-8)
+        };
+
+        assert!(close(
+            &shelf.b,
+            &[1.53512485958697, -2.69169618940638, 1.19839281085285]
+        ));
+        assert!(close(&shelf.a, &[-1.69065929318241, 0.73248077421585]));
+        assert!(close(&highpass.b, &[1.0, -2.0, 1.0]));
+        assert!(close(&highpass.a, &[-1.99004745483398, 0.99007225036621]));
+    }
+
+    #[test]
+    fn full_scale_sine() {
+        /*
+         * A 0 dBFS 997 Hz sine on one channel measures -3.01 LUFS, and on
+         * both channels of a stereo pair, 0 LUFS.
+         */
+        for (channels, expect) in [(1, -3.01), (2, 0.0)] {
+            let mut m = meter(channels);
+            m.process_f32(&sine(997.0, 0.0, 0.0, 5.0, channels));
+
+            let l = m.loudness();
+            for v in [l.momentary, l.short_term, l.integrated] {
+                assert!((v - expect).abs() < 0.05, "{channels}: {l:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn absolute_gate() {
+        /*
+         * Silence is below the absolute gate, so it does not pull the
+         * integrated loudness down.  Of the 50 blocks that end in the sine,
+         * the last three are only 3/4, 1/2, and 1/4 filled by it.
+         */
+        let mut m = meter(1);
+        m.process_f32(&sine(997.0, -20.0, 0.0, 5.0, 1));
+        m.process_f32(&vec![0.0; 10 * RATE as usize]);
+
+        let l = m.loudness();
+        assert_eq!(l.momentary, f64::NEG_INFINITY);
+        let expect = -23.01 + 10.0 * (48.5f64 / 50.0).log10();
+        assert!((l.integrated - expect).abs() < 0.02, "{l:?}");
+    }
+
+    #[test]
+    fn relative_gate() {
+        /*
+         * Audio 20 LU quieter than the rest is above the absolute gate, but
+         * more than 10 LU below the ungated mean, so it is discarded too;
+         * only the three blocks straddling the change remain.  Without the
+         * relative gate, this would measure about -26 LUFS.
+         */
+        let mut m = meter(1);
+        m.process_f32(&sine(997.0, -20.0, 0.0, 5.0, 1));
+        m.process_f32(&sine(997.0, -40.0, 0.0, 5.0, 1));
+
+        let l = m.loudness();
+        assert!((l.short_term + 43.01).abs() < 0.05, "{l:?}");
+        let straddling: f64 =
+            0.75 + 0.25 * 0.01 + 0.5 + 0.5 * 0.01 + 0.25 + 0.75 * 0.01;
+        let expect = -23.01 + 10.0 * ((47.0 + straddling) / 50.0).log10();
+        assert!((l.integrated - expect).abs() < 0.02, "{l:?}");
+    }
+
+    #[test]
+    fn silence() {
+        let mut m = meter(2);
+        m.process_f32(&[0.0; 2 * 48000]);
+
+        let l = m.loudness();
+        assert_eq!(l.integrated, f64::NEG_INFINITY);
+        assert_eq!(m.levels(0).peak_db(), f64::NEG_INFINITY);
+    }
+
+    #[test]
+    fn true_peak_between_samples() {
+        /*
+         * A quarter-rate sine sampled 45 degrees off its peaks never has a
+         * sample above -3 dBFS, but its true peak is at 0 dBTP.
+         */
+        let mut m = meter(1);
+        m.process_f32(&sine(12000.0, 0.0, std::f64::consts::FRAC_PI_4, 0.1, 1));
+
+        let levels = m.levels(0);
+        assert!((levels.peak_db() + 3.01).abs() < 0.01, "{levels:?}");
+        assert!(levels.true_peak_db().abs() < 0.5, "{levels:?}");
+        assert!((levels.rms_db() + 3.01).abs() < 0.01, "{levels:?}");
+        assert_eq!(levels.clips, 0);
+    }
+
+    #[test]
+    fn clips() {
+        let mut m = Meter::new(SampleFormat::S16LE, 1, RATE);
+        let buf: Vec<u8> = [i16::MAX, 0, i16::MIN, 100]
+            .iter()
+            .flat_map(|s| s.to_le_bytes())
+            .collect();
+        m.process(&buf);
+        assert_eq!(m.levels(0).clips, 2);
+    }
+}
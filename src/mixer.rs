@@ -1,6 +1,13 @@
-use std::{fs::File, os::fd::AsRawFd, path::Path};
+use std::{
+    fs::File,
+    os::fd::{AsFd, AsRawFd, BorrowedFd, RawFd},
+    path::Path,
+};
 
-use crate::{basic_ioctl, basic_ioctl_inout, c_chars_to_string, sys};
+use crate::{
+    basic_ioctl, basic_ioctl_inout, c_chars_to_string, c_int_to_u32, sys,
+    u32_to_c_int,
+};
 
 /**
  * The "/dev/mixer" pseudo-device allows enumeration of audio devices in the
@@ -50,93 +57,139 @@ impl Mixer {
     }
 
     pub fn sysinfo(&self) -> std::io::Result<SysInfo> {
-        let buf: sys::oss_sysinfo = basic_ioctl(&self.f, sys::SNDCTL_SYSINFO)?;
+        SysInfo::from_raw(&self.sysinfo_raw()?)
+    }
 
-        let product = c_chars_to_string(&buf.product).unwrap();
-        let version = c_chars_to_string(&buf.version).unwrap();
-        let licence = c_chars_to_string(&buf.license).unwrap();
+    pub fn audioinfo(&self, index: u32) -> std::io::Result<AudioInfo> {
+        AudioInfo::from_raw(&self.audioinfo_raw(index)?)
+    }
 
-        Ok(SysInfo {
-            product,
-            version,
-            maj: ((buf.versionnum as u32) & 0xFFFF0000u32) >> 16,
-            min: (buf.versionnum as u32) & 0xFFFFu32,
-            num_audios: buf.numaudios.try_into().unwrap(),
-            num_mixers: buf.nummixers.try_into().unwrap(),
-            num_cards: buf.numcards.try_into().unwrap(),
-            num_audio_engines: buf.numaudioengines.try_into().unwrap(),
-            licence,
-        })
+    pub fn cardinfo(&self, index: u32) -> std::io::Result<CardInfo> {
+        CardInfo::from_raw(&self.cardinfo_raw(index)?)
     }
 
-    pub fn audioinfo(&self, index: u32) -> std::io::Result<AudioInfo> {
-        let buf: sys::oss_audioinfo = basic_ioctl_inout(
+    pub fn mixerinfo(&self, index: u32) -> std::io::Result<MixerInfo> {
+        MixerInfo::from_raw(&self.mixerinfo_raw(index)?)
+    }
+
+    /*
+     * The decoded information structures replace any invalid UTF-8 in the
+     * strings reported by the driver.  These variants return the structures
+     * exactly as the driver filled them in, for consumers that need the
+     * original bytes; see sys::c_chars_bytes().
+     */
+
+    pub fn sysinfo_raw(&self) -> std::io::Result<sys::oss_sysinfo> {
+        basic_ioctl(&self.f, sys::SNDCTL_SYSINFO)
+    }
+
+    pub fn audioinfo_raw(
+        &self,
+        index: u32,
+    ) -> std::io::Result<sys::oss_audioinfo> {
+        basic_ioctl_inout(
             &self.f,
             sys::SNDCTL_AUDIOINFO,
             sys::oss_audioinfo {
-                dev: index.try_into().unwrap(),
+                dev: u32_to_c_int(index)?,
                 ..Default::default()
             },
-        )?;
-
-        let caps = sys::AudioCaps::from_bits(buf.caps).unwrap();
-        let caps_revision = (buf.caps & sys::PCM_CAP_REVISION) as u32;
-
-        Ok(AudioInfo {
-            dev: buf.dev.try_into().unwrap(),
-            name: c_chars_to_string(&buf.name).unwrap(),
-            card_number: buf.card_number.try_into().unwrap(),
-            mixer_dev: buf.mixer_dev.try_into().unwrap(),
-            caps,
-            caps_revision,
-            min_rate: buf.min_rate.try_into().unwrap(),
-            max_rate: buf.max_rate.try_into().unwrap(),
-            min_channels: buf.min_channels.try_into().unwrap(),
-            max_channels: buf.max_channels.try_into().unwrap(),
-            devnode: c_chars_to_string(&buf.devnode).unwrap(),
-        })
+        )
     }
 
-    pub fn cardinfo(&self, index: u32) -> std::io::Result<CardInfo> {
-        let buf: sys::oss_card_info = basic_ioctl_inout(
+    pub fn cardinfo_raw(
+        &self,
+        index: u32,
+    ) -> std::io::Result<sys::oss_card_info> {
+        basic_ioctl_inout(
             &self.f,
             sys::SNDCTL_CARDINFO,
             sys::oss_card_info {
-                card: index.try_into().unwrap(),
+                card: u32_to_c_int(index)?,
                 ..Default::default()
             },
-        )?;
-
-        Ok(CardInfo {
-            shortname: c_chars_to_string(&buf.shortname).unwrap(),
-            longname: c_chars_to_string(&buf.longname).unwrap(),
-            hw_info: c_chars_to_string(&buf.hw_info).unwrap(),
-        })
+        )
     }
 
-    pub fn mixerinfo(&self, index: u32) -> std::io::Result<MixerInfo> {
-        let buf: sys::oss_mixerinfo = basic_ioctl_inout(
+    pub fn mixerinfo_raw(
+        &self,
+        index: u32,
+    ) -> std::io::Result<sys::oss_mixerinfo> {
+        basic_ioctl_inout(
             &self.f,
             sys::SNDCTL_MIXERINFO,
             sys::oss_mixerinfo {
-                dev: index.try_into().unwrap(),
+                dev: u32_to_c_int(index)?,
                 ..Default::default()
             },
-        )?;
+        )
+    }
 
-        Ok(MixerInfo {
-            dev: buf.dev.try_into().unwrap(),
-            name: c_chars_to_string(&buf.name).unwrap(),
-            card_number: buf.card_number.try_into().unwrap(),
-            modify_counter: buf.modify_counter.try_into().unwrap(),
-            nrext: buf.nrext.try_into().unwrap(),
-            priority: buf.priority,
-            devnode: c_chars_to_string(&buf.devnode).unwrap(),
+    /**
+     * Iterate over the audio devices in the system, as counted by sysinfo().
+     */
+    pub fn audio_devices(
+        &self,
+    ) -> std::io::Result<impl Iterator<Item = std::io::Result<AudioInfo>> + '_>
+    {
+        let si = self.sysinfo()?;
+        Ok((0..si.num_audios).map(|i| self.audioinfo(i)))
+    }
+
+    pub fn cards(
+        &self,
+    ) -> std::io::Result<impl Iterator<Item = std::io::Result<CardInfo>> + '_>
+    {
+        let si = self.sysinfo()?;
+        Ok((0..si.num_cards).map(|i| self.cardinfo(i)))
+    }
+
+    pub fn mixers(
+        &self,
+    ) -> std::io::Result<impl Iterator<Item = std::io::Result<MixerInfo>> + '_>
+    {
+        let si = self.sysinfo()?;
+        Ok((0..si.num_mixers).map(|i| self.mixerinfo(i)))
+    }
+
+    /**
+     * Capture the system information and every audio device, card, and mixer
+     * in one call.  An entry the driver cannot describe (e.g., a device that
+     * was removed while the snapshot was being taken) is left out, rather
+     * than failing the whole snapshot.
+     */
+    pub fn snapshot(&self) -> std::io::Result<SystemSnapshot> {
+        let sysinfo = self.sysinfo()?;
+
+        Ok(SystemSnapshot {
+            audio_devices: (0..sysinfo.num_audios)
+                .filter_map(|i| self.audioinfo(i).ok())
+                .collect(),
+            cards: (0..sysinfo.num_cards)
+                .filter_map(|i| self.cardinfo(i).ok())
+                .collect(),
+            mixers: (0..sysinfo.num_mixers)
+                .filter_map(|i| self.mixerinfo(i).ok())
+                .collect(),
+            sysinfo,
         })
     }
 }
 
+impl AsFd for Mixer {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.f.as_fd()
+    }
+}
+
+impl AsRawFd for Mixer {
+    fn as_raw_fd(&self) -> RawFd {
+        self.f.as_raw_fd()
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct SysInfo {
     pub product: String,
     pub version: String,
@@ -149,12 +202,35 @@ pub struct SysInfo {
     pub licence: String,
 }
 
+impl SysInfo {
+    pub(crate) fn from_raw(buf: &sys::oss_sysinfo) -> std::io::Result<Self> {
+        Ok(SysInfo {
+            product: c_chars_to_string(&buf.product),
+            version: c_chars_to_string(&buf.version),
+            maj: ((buf.versionnum as u32) & 0xFFFF0000u32) >> 16,
+            min: (buf.versionnum as u32) & 0xFFFFu32,
+            num_audios: c_int_to_u32(buf.numaudios)?,
+            num_mixers: c_int_to_u32(buf.nummixers)?,
+            num_cards: c_int_to_u32(buf.numcards)?,
+            num_audio_engines: c_int_to_u32(buf.numaudioengines)?,
+            licence: c_chars_to_string(&buf.license),
+        })
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct AudioInfo {
     pub dev: u32,
     pub name: String,
-    pub card_number: u32,
-    pub mixer_dev: u32,
+    /**
+     * The card the device belongs to, if the driver reports one.
+     */
+    pub card_number: Option<u32>,
+    /**
+     * The mixer device associated with this audio device, if there is one.
+     */
+    pub mixer_dev: Option<u32>,
     pub caps_revision: u32,
     pub caps: sys::AudioCaps,
     pub min_rate: u32,
@@ -162,22 +238,270 @@ pub struct AudioInfo {
     pub min_channels: u32,
     pub max_channels: u32,
     pub devnode: String,
+    /**
+     * Sample formats supported for recording and for playback.
+     */
+    pub iformats: sys::AudioFormats,
+    pub oformats: sys::AudioFormats,
+    /**
+     * Whether the device is currently open, and in which directions.
+     */
+    pub busy: sys::AudioBusy,
+    /**
+     * The process that has the device open, if the driver reports one.
+     */
+    pub owner: Option<AudioOwner>,
+    /**
+     * The discrete sample rates the device supports.  If this is empty, any
+     * rate from min_rate to max_rate may be used.
+     */
+    pub rates: Vec<u32>,
+    pub label: String,
+    /**
+     * The latency of the device in microseconds, if known.
+     */
+    pub latency: Option<u32>,
+    pub handle: String,
+    pub enabled: bool,
+    /**
+     * Additional engines for the same device, used to satisfy concurrent
+     * opens.
+     */
+    pub next_play_engine: Option<u32>,
+    pub next_rec_engine: Option<u32>,
+}
+
+impl AudioInfo {
+    /**
+     * Decode the driver's description of an audio device, as returned by
+     * SNDCTL_AUDIOINFO on either the mixer or a DSP device.
+     */
+    pub(crate) fn from_raw(buf: &sys::oss_audioinfo) -> std::io::Result<Self> {
+        let caps = sys::AudioCaps::from_bits_retain(buf.caps);
+        let caps_revision = (buf.caps & sys::PCM_CAP_REVISION) as u32;
+
+        /*
+         * If the driver lists no discrete rates, any rate between the minimum
+         * and maximum is supported.
+         */
+        let nrates = (buf.nrates as usize).min(sys::OSS_MAX_SAMPLE_RATES);
+        let rates = buf.rates[..nrates].to_vec();
+
+        /*
+         * The process ID is only meaningful while the device is open.
+         */
+        let busy = sys::AudioBusy::from_bits_retain(buf.busy);
+        let owner = if !busy.is_empty() && buf.pid > 0 {
+            Some(AudioOwner {
+                pid: buf.pid as u32,
+                cmd: c_chars_to_string(&buf.cmd),
+            })
+        } else {
+            None
+        };
+
+        Ok(AudioInfo {
+            dev: c_int_to_u32(buf.dev)?,
+            name: c_chars_to_string(&buf.name),
+            card_number: u32::try_from(buf.card_number).ok(),
+            mixer_dev: u32::try_from(buf.mixer_dev).ok(),
+            caps,
+            caps_revision,
+            min_rate: non_negative(buf.min_rate),
+            max_rate: non_negative(buf.max_rate),
+            min_channels: non_negative(buf.min_channels),
+            max_channels: non_negative(buf.max_channels),
+            devnode: c_chars_to_string(&buf.devnode),
+            iformats: sys::AudioFormats::from_bits_retain(buf.iformats),
+            oformats: sys::AudioFormats::from_bits_retain(buf.oformats),
+            busy,
+            owner,
+            rates,
+            label: c_chars_to_string(&buf.label),
+            latency: u32::try_from(buf.latency).ok(),
+            handle: c_chars_to_string(&buf.handle),
+            enabled: buf.enabled != 0,
+            next_play_engine: u32::try_from(buf.next_play_engine)
+                .ok()
+                .filter(|&e| e > 0),
+            next_rec_engine: u32::try_from(buf.next_rec_engine)
+                .ok()
+                .filter(|&e| e > 0),
+        })
+    }
+
+    /**
+     * Whether the device supports the given sample rate.
+     */
+    pub fn supports_rate(&self, rate: u32) -> bool {
+        if self.rates.is_empty() {
+            (self.min_rate..=self.max_rate).contains(&rate)
+        } else {
+            self.rates.contains(&rate)
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AudioOwner {
+    pub pid: u32,
+    pub cmd: String,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct CardInfo {
+    pub card: u32,
     pub shortname: String,
     pub longname: String,
     pub hw_info: String,
 }
 
+impl CardInfo {
+    pub(crate) fn from_raw(buf: &sys::oss_card_info) -> std::io::Result<Self> {
+        Ok(CardInfo {
+            card: c_int_to_u32(buf.card)?,
+            shortname: c_chars_to_string(&buf.shortname),
+            longname: c_chars_to_string(&buf.longname),
+            hw_info: c_chars_to_string(&buf.hw_info),
+        })
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct MixerInfo {
     pub dev: u32,
     pub name: String,
     pub modify_counter: u32,
-    pub card_number: u32,
+    /**
+     * The card the mixer belongs to, if the driver reports one.
+     */
+    pub card_number: Option<u32>,
     pub nrext: u32,
     pub priority: i32,
     pub devnode: String,
 }
+
+impl MixerInfo {
+    pub(crate) fn from_raw(buf: &sys::oss_mixerinfo) -> std::io::Result<Self> {
+        Ok(MixerInfo {
+            dev: c_int_to_u32(buf.dev)?,
+            name: c_chars_to_string(&buf.name),
+            card_number: u32::try_from(buf.card_number).ok(),
+            /*
+             * This is a counter that only ever increases, so treat it as
+             * unsigned in case it has wrapped.
+             */
+            modify_counter: buf.modify_counter as u32,
+            nrext: non_negative(buf.nrext),
+            priority: buf.priority,
+            devnode: c_chars_to_string(&buf.devnode),
+        })
+    }
+}
+
+/**
+ * Everything the mixer device can tell us about the audio hardware in the
+ * system at a single point in time.  Audio devices refer to their card and
+ * mixer by number; the card() and mixer() methods follow those links.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SystemSnapshot {
+    pub sysinfo: SysInfo,
+    pub audio_devices: Vec<AudioInfo>,
+    pub cards: Vec<CardInfo>,
+    pub mixers: Vec<MixerInfo>,
+}
+
+impl SystemSnapshot {
+    pub fn card(&self, audio: &AudioInfo) -> Option<&CardInfo> {
+        self.cards.iter().find(|c| Some(c.card) == audio.card_number)
+    }
+
+    pub fn mixer(&self, audio: &AudioInfo) -> Option<&MixerInfo> {
+        self.mixers.iter().find(|m| Some(m.dev) == audio.mixer_dev)
+    }
+
+    pub fn card_audio_devices(
+        &self,
+        card: &CardInfo,
+    ) -> impl Iterator<Item = &AudioInfo> {
+        let card = card.card;
+        self.audio_devices.iter().filter(move |a| a.card_number == Some(card))
+    }
+
+    pub fn card_mixers(
+        &self,
+        card: &CardInfo,
+    ) -> impl Iterator<Item = &MixerInfo> {
+        let card = card.card;
+        self.mixers.iter().filter(move |m| m.card_number == Some(card))
+    }
+}
+
+/**
+ * Convert a count or limit reported by the driver, treating a nonsensical
+ * negative value as zero rather than rejecting the whole entry.
+ */
+fn non_negative(v: libc::c_int) -> u32 {
+    u32::try_from(v).unwrap_or(0)
+}
+
+#[cfg(all(test, feature = "serde"))]
+mod tests {
+    use super::*;
+
+    fn set(dst: &mut [libc::c_char], s: &str) {
+        for (d, b) in dst.iter_mut().zip(s.bytes()) {
+            *d = b as libc::c_char;
+        }
+    }
+
+    #[test]
+    fn audioinfo_json_round_trip() {
+        let mut raw = sys::oss_audioinfo {
+            dev: 1,
+            card_number: -1,
+            mixer_dev: 0,
+            caps: (sys::AudioCaps::PCM_CAP_OUTPUT
+                | sys::AudioCaps::PCM_CAP_DEFAULT)
+                .bits()
+                | 0x03,
+            oformats: (sys::AudioFormats::AFMT_S16_LE
+                | sys::AudioFormats::AFMT_S32_LE)
+                .bits(),
+            min_rate: 8000,
+            max_rate: 192000,
+            max_channels: 2,
+            nrates: 2,
+            enabled: 1,
+            ..Default::default()
+        };
+        raw.rates[..2].copy_from_slice(&[44100, 48000]);
+        set(&mut raw.name, "HD Audio");
+        set(&mut raw.devnode, "/dev/sound/audiohd:0dsp");
+        let info = AudioInfo::from_raw(&raw).unwrap();
+
+        let json = serde_json::to_value(&info).unwrap();
+        assert_eq!(
+            json["caps"],
+            serde_json::json!([
+                "PCM_CAP_OUTPUT",
+                "PCM_CAP_DEFAULT",
+                "0x00000003"
+            ])
+        );
+        assert_eq!(
+            json["oformats"],
+            serde_json::json!(["AFMT_S16_LE", "AFMT_S32_LE"])
+        );
+        assert_eq!(json["card_number"], serde_json::Value::Null);
+
+        let back: AudioInfo = serde_json::from_value(json).unwrap();
+        assert_eq!(back, info);
+        assert_eq!(back.caps_revision, 3);
+    }
+}
@@ -0,0 +1,357 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc,
+    },
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use crate::{
+    mixer::{AudioInfo, CardInfo, MixerInfo, SysInfo},
+    Mixer,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceEvent {
+    Added(AudioInfo),
+    Removed(AudioInfo),
+    /**
+     * Something about the device changed; e.g., it was opened or closed by
+     * some process, or was enabled or disabled.  A device that has only been
+     * renumbered, as when an earlier one goes away, is not reported.
+     */
+    Changed {
+        old: Box<AudioInfo>,
+        new: Box<AudioInfo>,
+    },
+    CardAdded(CardInfo),
+    CardRemoved(CardInfo),
+    MixerAdded(MixerInfo),
+    MixerRemoved(MixerInfo),
+}
+
+impl DeviceEvent {
+    /**
+     * The most recent description of the audio device the event is about, or
+     * None for a card or mixer event.
+     */
+    pub fn info(&self) -> Option<&AudioInfo> {
+        match self {
+            DeviceEvent::Added(info) | DeviceEvent::Removed(info) => Some(info),
+            DeviceEvent::Changed { new, .. } => Some(new),
+            _ => None,
+        }
+    }
+}
+
+/**
+ * Watches for audio devices, cards, and mixers coming and going; e.g., as USB
+ * headsets are attached and detached.  Each call to poll() reads the system
+ * information from the mixer and compares the audio devices it reports with
+ * those seen last time, which also picks up devices being opened and closed.
+ * Cards and mixers are rescanned whenever the number of audio devices, cards,
+ * or mixers in the system information changes, or an audio device comes or
+ * goes.
+ *
+ * Device numbers are reassigned as devices are removed, so audio devices and
+ * mixers are matched by their device node instead, and cards by their names
+ * and hardware description.
+ */
+pub struct DeviceMonitor {
+    mixer: Mixer,
+    sysinfo: SysInfo,
+    devices: Vec<AudioInfo>,
+    cards: Vec<CardInfo>,
+    mixers: Vec<MixerInfo>,
+}
+
+impl DeviceMonitor {
+    /**
+     * Create a monitor.  The devices present now are treated as already
+     * known, and will not be reported as added.
+     */
+    pub fn new(mixer: Mixer) -> std::io::Result<Self> {
+        let sysinfo = mixer.sysinfo()?;
+        let devices = scan_devices(&mixer, &sysinfo);
+        let cards = scan_cards(&mixer, &sysinfo);
+        let mixers = scan_mixers(&mixer, &sysinfo);
+        Ok(DeviceMonitor { mixer, sysinfo, devices, cards, mixers })
+    }
+
+    /**
+     * The system information read by the last successful poll().
+     */
+    pub fn sysinfo(&self) -> &SysInfo {
+        &self.sysinfo
+    }
+
+    pub fn devices(&self) -> &[AudioInfo] {
+        &self.devices
+    }
+
+    pub fn cards(&self) -> &[CardInfo] {
+        &self.cards
+    }
+
+    pub fn mixers(&self) -> &[MixerInfo] {
+        &self.mixers
+    }
+
+    /**
+     * Rescan the devices in the system and return what has changed since the
+     * last scan.  Cards and mixers that appeared are reported first, and
+     * those that went away last, so that an audio device is never reported
+     * without its card.  If the system information cannot be read, an error
+     * is returned and the next poll() will compare against the last
+     * successful scan.
+     */
+    pub fn poll(&mut self) -> std::io::Result<Vec<DeviceEvent>> {
+        let sysinfo = self.mixer.sysinfo()?;
+        let devices = scan_devices(&self.mixer, &sysinfo);
+        let mut events = Vec::new();
+
+        for old in &self.devices {
+            match devices.iter().find(|d| d.devnode == old.devnode) {
+                None => events.push(DeviceEvent::Removed(old.clone())),
+                Some(new) if !same_device(old, new) => {
+                    events.push(DeviceEvent::Changed {
+                        old: Box::new(old.clone()),
+                        new: Box::new(new.clone()),
+                    })
+                }
+                Some(_) => (),
+            }
+        }
+        for new in &devices {
+            if !self.devices.iter().any(|d| d.devnode == new.devnode) {
+                events.push(DeviceEvent::Added(new.clone()));
+            }
+        }
+
+        let hotplug = sysinfo.num_audios != self.sysinfo.num_audios
+            || sysinfo.num_cards != self.sysinfo.num_cards
+            || sysinfo.num_mixers != self.sysinfo.num_mixers
+            || events.iter().any(|e| {
+                matches!(e, DeviceEvent::Added(_) | DeviceEvent::Removed(_))
+            });
+
+        if hotplug {
+            let cards = scan_cards(&self.mixer, &sysinfo);
+            let mixers = scan_mixers(&self.mixer, &sysinfo);
+
+            let (added, removed) = diff(&self.cards, &cards, same_card);
+            let mut first: Vec<_> =
+                added.into_iter().map(DeviceEvent::CardAdded).collect();
+            let mut last: Vec<_> =
+                removed.into_iter().map(DeviceEvent::CardRemoved).collect();
+
+            let (added, removed) =
+                diff(&self.mixers, &mixers, |a, b| a.devnode == b.devnode);
+            first.extend(added.into_iter().map(DeviceEvent::MixerAdded));
+            last.extend(removed.into_iter().map(DeviceEvent::MixerRemoved));
+
+            first.append(&mut events);
+            first.append(&mut last);
+            events = first;
+
+            self.cards = cards;
+            self.mixers = mixers;
+        }
+
+        self.sysinfo = sysinfo;
+        self.devices = devices;
+        Ok(events)
+    }
+
+    /**
+     * Poll for changes on a new thread every "interval", passing each event
+     * to the callback.  Failed scans are retried at the next interval.
+     */
+    pub fn spawn<F>(
+        mut self,
+        interval: Duration,
+        mut callback: F,
+    ) -> std::io::Result<MonitorHandle>
+    where
+        F: FnMut(DeviceEvent) + Send + 'static,
+    {
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let thread = {
+            let stop = Arc::clone(&stop);
+            std::thread::Builder::new().name("audio-monitor".into()).spawn(
+                move || {
+                    while !stop.load(Ordering::Relaxed) {
+                        std::thread::park_timeout(interval);
+                        if stop.load(Ordering::Relaxed) {
+                            break;
+                        }
+
+                        if let Ok(events) = self.poll() {
+                            events.into_iter().for_each(&mut callback);
+                        }
+                    }
+                    self
+                },
+            )?
+        };
+
+        Ok(MonitorHandle { stop, thread: Some(thread) })
+    }
+
+    /**
+     * Like spawn(), but deliver events through a channel.  Events are
+     * discarded once the receiver has been dropped; the thread continues
+     * until the handle is stopped or dropped.
+     */
+    pub fn channel(
+        self,
+        interval: Duration,
+    ) -> std::io::Result<(MonitorHandle, mpsc::Receiver<DeviceEvent>)> {
+        let (tx, rx) = mpsc::channel();
+        let handle = self.spawn(interval, move |ev| {
+            tx.send(ev).ok();
+        })?;
+        Ok((handle, rx))
+    }
+}
+
+/**
+ * Controls a monitor thread started with DeviceMonitor::spawn().  Dropping
+ * the handle stops the thread.
+ */
+pub struct MonitorHandle {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<DeviceMonitor>>,
+}
+
+impl MonitorHandle {
+    /**
+     * Stop the monitor thread and return the monitor, so that it may be
+     * polled directly or spawned again without losing track of the devices
+     * it has seen.
+     */
+    pub fn stop(mut self) -> DeviceMonitor {
+        self.join()
+    }
+
+    fn join(&mut self) -> DeviceMonitor {
+        self.stop.store(true, Ordering::Relaxed);
+        let thread = self.thread.take().unwrap();
+        thread.thread().unpark();
+        match thread.join() {
+            Ok(monitor) => monitor,
+            Err(p) => std::panic::resume_unwind(p),
+        }
+    }
+}
+
+impl Drop for MonitorHandle {
+    fn drop(&mut self) {
+        if self.thread.is_some() {
+            self.join();
+        }
+    }
+}
+
+/*
+ * In each of the scans below, an entry the driver cannot describe (e.g., one
+ * that disappeared after we read the system information) is skipped, rather
+ * than failing the whole scan.
+ */
+
+fn scan_devices(mixer: &Mixer, sysinfo: &SysInfo) -> Vec<AudioInfo> {
+    (0..sysinfo.num_audios).filter_map(|i| mixer.audioinfo(i).ok()).collect()
+}
+
+fn scan_cards(mixer: &Mixer, sysinfo: &SysInfo) -> Vec<CardInfo> {
+    (0..sysinfo.num_cards).filter_map(|i| mixer.cardinfo(i).ok()).collect()
+}
+
+fn scan_mixers(mixer: &Mixer, sysinfo: &SysInfo) -> Vec<MixerInfo> {
+    (0..sysinfo.num_mixers).filter_map(|i| mixer.mixerinfo(i).ok()).collect()
+}
+
+/**
+ * Compare what the driver says about a device, ignoring the device, card,
+ * mixer, and engine numbers: these are positions in the enumeration, which
+ * shift when an earlier device goes away.
+ */
+fn same_device(a: &AudioInfo, b: &AudioInfo) -> bool {
+    a.devnode == b.devnode
+        && a.name == b.name
+        && a.handle == b.handle
+        && a.label == b.label
+        && a.caps_revision == b.caps_revision
+        && a.caps == b.caps
+        && a.min_rate == b.min_rate
+        && a.max_rate == b.max_rate
+        && a.min_channels == b.min_channels
+        && a.max_channels == b.max_channels
+        && a.rates == b.rates
+        && a.iformats == b.iformats
+        && a.oformats == b.oformats
+        && a.busy == b.busy
+        && a.owner == b.owner
+        && a.latency == b.latency
+        && a.enabled == b.enabled
+}
+
+/**
+ * Card numbers are reassigned too, so compare everything else.
+ */
+fn same_card(a: &CardInfo, b: &CardInfo) -> bool {
+    a.shortname == b.shortname
+        && a.longname == b.longname
+        && a.hw_info == b.hw_info
+}
+
+/**
+ * Return the entries of "new" that are not in "old", and those of "old" that
+ * are not in "new".
+ */
+fn diff<T: Clone>(
+    old: &[T],
+    new: &[T],
+    same: impl Fn(&T, &T) -> bool,
+) -> (Vec<T>, Vec<T>) {
+    let added = new
+        .iter()
+        .filter(|n| !old.iter().any(|o| same(o, n)))
+        .cloned()
+        .collect();
+    let removed = old
+        .iter()
+        .filter(|o| !new.iter().any(|n| same(o, n)))
+        .cloned()
+        .collect();
+    (added, removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sys;
+
+    fn info(dev: u32) -> AudioInfo {
+        let mut raw: sys::oss_audioinfo = unsafe { std::mem::zeroed() };
+        raw.dev = dev as libc::c_int;
+        raw.card_number = dev as libc::c_int;
+        raw.mixer_dev = dev as libc::c_int;
+        AudioInfo::from_raw(&raw).unwrap()
+    }
+
+    #[test]
+    fn renumbered_device_is_unchanged() {
+        let old = info(3);
+        let new = info(2);
+        assert_ne!(old, new);
+        assert!(same_device(&old, &new));
+
+        let busy = AudioInfo { busy: sys::AudioBusy::all(), ..new.clone() };
+        assert!(!same_device(&old, &busy));
+        let disabled = AudioInfo { enabled: !new.enabled, ..new };
+        assert!(!same_device(&old, &disabled));
+    }
+}
@@ -0,0 +1,861 @@
+/*
+ * A simple audio pipeline: a Source, a series of Processors, and a Sink,
+ * exchanging blocks of interleaved floating point frames.  Each node states
+ * the rate and channel count it works in, and the pipeline converts between
+ * adjacent nodes where they differ, so that, e.g., playing a 44.1 kHz mono
+ * file on a 48 kHz stereo device needs no special handling.
+ */
+
+use std::{
+    io::{Read, Write},
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    generator::Generator,
+    resample::Resampler,
+    sample::{add_mapped, SampleFormat},
+    stream::{configure, StreamConfig},
+    Dsp,
+};
+
+/*
+ * The block size used by Pipeline::new().
+ */
+pub const DEFAULT_BLOCK_FRAMES: usize = 1024;
+
+/**
+ * The shape of the audio passed between pipeline nodes.  Samples are always
+ * f32, nominally in the range [-1.0, 1.0].
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Format {
+    pub channels: usize,
+    pub rate: u32,
+}
+
+impl Format {
+    pub fn new(channels: usize, rate: u32) -> Self {
+        Format { channels, rate }
+    }
+
+    /**
+     * The pipeline format corresponding to a Stream configuration, along with
+     * the sample format to convert to and from.  Fails with EINVAL if the
+     * format is not one we can convert.
+     */
+    pub fn from_stream(
+        config: &StreamConfig,
+    ) -> std::io::Result<(Self, SampleFormat)> {
+        let format = SampleFormat::from_audio_format(config.format)
+            .ok_or_else(|| std::io::Error::from_raw_os_error(libc::EINVAL))?;
+        Ok((Format::new(config.channels as usize, config.rate), format))
+    }
+}
+
+pub trait Source: Send {
+    fn format(&self) -> Format;
+
+    /**
+     * Fill the start of "buf" with interleaved frames, returning the number
+     * of frames produced.  This may be fewer than will fit, but is zero only
+     * once the source has ended.
+     */
+    fn read(&mut self, buf: &mut [f32]) -> std::io::Result<usize>;
+}
+
+pub trait Processor: Send {
+    /**
+     * The format this processor was built for, if it cares.  The pipeline
+     * converts its input to match.
+     */
+    fn format(&self) -> Option<Format> {
+        None
+    }
+
+    /**
+     * Process a block of interleaved frames in place.
+     */
+    fn process_block(&mut self, buf: &mut [f32]);
+}
+
+pub trait Sink: Send {
+    fn format(&self) -> Format;
+
+    fn write(&mut self, buf: &[f32]) -> std::io::Result<()>;
+
+    /**
+     * Called once the pipeline has finished running, e.g., to wait for
+     * buffered audio to be played.
+     */
+    fn finish(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/**
+ * A processor that is shared with another thread; e.g., a Meter whose levels
+ * are displayed while the pipeline runs.
+ */
+impl<P: Processor> Processor for Arc<Mutex<P>> {
+    fn format(&self) -> Option<Format> {
+        self.lock().unwrap().format()
+    }
+
+    fn process_block(&mut self, buf: &mut [f32]) {
+        self.lock().unwrap().process_block(buf)
+    }
+}
+
+/**
+ * Converts blocks from one format to another: first the rate, and then the
+ * channel layout.
+ */
+struct Converter {
+    from: Format,
+    to: Format,
+    resampler: Resampler,
+    resampled: Vec<f32>,
+    out: Vec<f32>,
+    /*
+     * Enough silence to push out what the resampler holds back.
+     */
+    silence: Vec<f32>,
+}
+
+impl Converter {
+    /**
+     * Create a converter for blocks of up to "frames" frames, returning it
+     * along with the largest number of frames it will produce from a block.
+     */
+    fn new(
+        from: Format,
+        to: Format,
+        frames: usize,
+    ) -> std::io::Result<(Self, usize)> {
+        if to.channels == 0 {
+            return Err(std::io::Error::from_raw_os_error(libc::EINVAL));
+        }
+
+        let resampler = Resampler::new(from.channels, from.rate, to.rate)?;
+        let delay = resampler.delay();
+
+        /*
+         * Allow for the fractional position carried between blocks, and for
+         * the silence fed in by flush().
+         */
+        let max = (frames.max(delay) as u64 * u64::from(to.rate))
+            .div_ceil(u64::from(from.rate.max(1))) as usize
+            + 2;
+
+        let c = Converter {
+            from,
+            to,
+            resampler,
+            resampled: vec![0.0; max * from.channels],
+            out: vec![0.0; max * to.channels],
+            silence: vec![0.0; delay * from.channels],
+        };
+        Ok((c, max))
+    }
+
+    fn convert(&mut self, input: &[f32]) -> &mut [f32] {
+        let n = self.convert_into_out(input);
+        &mut self.out[..n]
+    }
+
+    /**
+     * Push the frames held in the resampler out with silence, as at the end
+     * of the stream, and leave it ready to start afresh.
+     */
+    fn flush(&mut self) -> &mut [f32] {
+        let silence = std::mem::take(&mut self.silence);
+        let n = self.convert_into_out(&silence);
+        self.silence = silence;
+        self.resampler.reset();
+        &mut self.out[..n]
+    }
+
+    /**
+     * Convert "input" into the start of "out", returning the number of
+     * samples written there.
+     */
+    fn convert_into_out(&mut self, input: &[f32]) -> usize {
+        let fc = self.from.channels;
+        let tc = self.to.channels;
+
+        let (used, made) = self.resampler.process(input, &mut self.resampled);
+        /*
+         * "resampled" has room for everything a block can produce (see
+         * new()), so the resampler never stops short of the end of the input.
+         */
+        debug_assert_eq!(used, input.len() / fc);
+
+        let out = &mut self.out[..made * tc];
+        out.fill(0.0);
+        add_mapped(&self.resampled[..made * fc], fc, out, tc);
+        made * tc
+    }
+}
+
+enum Stage {
+    Process(Box<dyn Processor>),
+    Convert(Converter),
+}
+
+/**
+ * Pulls blocks of frames from a Source, passes them through each Processor
+ * in the order they were added, and writes the result to a Sink.  The block
+ * size is fixed when the pipeline is created, and every buffer is allocated
+ * before it runs.
+ */
+pub struct Pipeline {
+    source: Box<dyn Source>,
+    block: usize,
+    buf: Vec<f32>,
+    stages: Vec<Stage>,
+    format: Format,
+    /*
+     * The largest number of frames the last stage produces from one block.
+     */
+    frames: usize,
+    /*
+     * The conversion to the format of the sink, if it differs.  This is kept
+     * between runs so that a stream run in pieces is converted seamlessly.
+     */
+    tail: Option<Converter>,
+}
+
+impl Pipeline {
+    pub fn new<S: Source + 'static>(source: S) -> std::io::Result<Self> {
+        Self::with_block_frames(source, DEFAULT_BLOCK_FRAMES)
+    }
+
+    /**
+     * Create a pipeline that reads up to "block" frames from the source at a
+     * time.  Fails with EINVAL if the block size, or the channel count or rate
+     * of the source, is zero.
+     */
+    pub fn with_block_frames<S: Source + 'static>(
+        source: S,
+        block: usize,
+    ) -> std::io::Result<Self> {
+        let format = source.format();
+        if block == 0 || format.channels == 0 || format.rate == 0 {
+            return Err(std::io::Error::from_raw_os_error(libc::EINVAL));
+        }
+
+        Ok(Pipeline {
+            source: Box::new(source),
+            block,
+            buf: vec![0.0; block * format.channels],
+            stages: Vec::new(),
+            format,
+            frames: block,
+            tail: None,
+        })
+    }
+
+    /**
+     * The format produced by the last node added so far.
+     */
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    pub fn block_frames(&self) -> usize {
+        self.block
+    }
+
+    /**
+     * Convert the audio to the given format from here on.  This is done
+     * automatically where needed, but may also be used, e.g., to mix down to
+     * mono before processors that accept any format.  Fails with EINVAL if
+     * the channel count or rate is zero.
+     */
+    pub fn convert(&mut self, format: Format) -> std::io::Result<()> {
+        if format == self.format {
+            return Ok(());
+        }
+
+        let (c, frames) = Converter::new(self.format, format, self.frames)?;
+        self.stages.push(Stage::Convert(c));
+        self.format = format;
+        self.frames = frames;
+        Ok(())
+    }
+
+    /**
+     * Add a processor to the end of the pipeline, converting to its format
+     * first if necessary.
+     */
+    pub fn push<P: Processor + 'static>(
+        &mut self,
+        processor: P,
+    ) -> std::io::Result<()> {
+        if let Some(format) = processor.format() {
+            self.convert(format)?;
+        }
+        self.stages.push(Stage::Process(Box::new(processor)));
+        Ok(())
+    }
+
+    /**
+     * Run until the source ends, converting the output to the format of the
+     * sink if necessary, and then finish the sink.  Returns the number of
+     * frames read from the source.  Once the source has ended, the audio
+     * held back by sample rate conversion is pushed out with silence.
+     */
+    pub fn run<K: Sink + ?Sized>(
+        &mut self,
+        sink: &mut K,
+    ) -> std::io::Result<u64> {
+        self.run_frames(sink, u64::MAX)
+    }
+
+    /**
+     * Like run(), but stop after reading "limit" frames from the source;
+     * e.g., for a capture source, which never ends.
+     */
+    pub fn run_frames<K: Sink + ?Sized>(
+        &mut self,
+        sink: &mut K,
+        limit: u64,
+    ) -> std::io::Result<u64> {
+        let ch = self.source.format().channels;
+
+        let to = sink.format();
+        if to == self.format {
+            self.tail = None;
+        } else if self
+            .tail
+            .as_ref()
+            .is_none_or(|c| c.from != self.format || c.to != to)
+        {
+            self.tail = Some(Converter::new(self.format, to, self.frames)?.0);
+        }
+
+        let mut total = 0;
+        while total < limit {
+            let want = (limit - total).min(self.block as u64) as usize;
+            let n = self.source.read(&mut self.buf[..want * ch])?;
+            if n == 0 {
+                self.flush(sink)?;
+                break;
+            }
+            total += n as u64;
+
+            let data = &mut self.buf[..n * ch];
+            write_through(&mut self.stages, self.tail.as_mut(), data, sink)?;
+        }
+
+        sink.finish()?;
+        Ok(total)
+    }
+
+    /**
+     * Push the frames held back by each converter through the rest of the
+     * pipeline, in order, once the source has ended.
+     */
+    fn flush<K: Sink + ?Sized>(&mut self, sink: &mut K) -> std::io::Result<()> {
+        for i in 0..self.stages.len() {
+            let (head, rest) = self.stages.split_at_mut(i + 1);
+            if let Stage::Convert(c) = &mut head[i] {
+                let data = c.flush();
+                if !data.is_empty() {
+                    write_through(rest, self.tail.as_mut(), data, sink)?;
+                }
+            }
+        }
+
+        if let Some(c) = &mut self.tail {
+            let data = c.flush();
+            if !data.is_empty() {
+                sink.write(data)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/**
+ * Pass a block through the given stages and the conversion for the sink, and
+ * write the result to the sink.
+ */
+fn write_through<'a, K: Sink + ?Sized>(
+    stages: &'a mut [Stage],
+    tail: Option<&'a mut Converter>,
+    mut data: &'a mut [f32],
+    sink: &mut K,
+) -> std::io::Result<()> {
+    for stage in stages {
+        data = match stage {
+            Stage::Process(p) => {
+                p.process_block(data);
+                data
+            }
+            Stage::Convert(c) => c.convert(data),
+        };
+    }
+    if let Some(c) = tail {
+        data = c.convert(data);
+    }
+
+    sink.write(data)
+}
+
+/**
+ * Whole frames read from a byte source, with any partial frame at the end of
+ * a read held over for the next.
+ */
+struct Frames {
+    format: SampleFormat,
+    frame_size: usize,
+    bytes: Vec<u8>,
+    have: usize,
+}
+
+impl Frames {
+    fn new(format: SampleFormat, channels: usize) -> std::io::Result<Self> {
+        let frame_size = format.size() * channels;
+        if frame_size == 0 {
+            return Err(std::io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        Ok(Frames { format, frame_size, bytes: Vec::new(), have: 0 })
+    }
+
+    /**
+     * Read and decode up to buf.len() samples' worth of whole frames, calling
+     * "f" until at least one whole frame is available or it returns zero.
+     */
+    fn read<F>(&mut self, buf: &mut [f32], mut f: F) -> std::io::Result<usize>
+    where
+        F: FnMut(&mut [u8]) -> std::io::Result<usize>,
+    {
+        let fs = self.frame_size;
+        let want = buf.len() / (fs / self.format.size()) * fs;
+        if want == 0 {
+            return Ok(0);
+        }
+        if self.bytes.len() < want {
+            self.bytes.resize(want, 0);
+        }
+
+        while self.have < fs {
+            let n = f(&mut self.bytes[self.have..want])?;
+            if n == 0 {
+                break;
+            }
+            self.have += n;
+        }
+
+        let frames = self.have / fs;
+        let used = frames * fs;
+        self.format.decode_slice(&self.bytes[..used], buf);
+        self.bytes.copy_within(used..self.have, 0);
+        self.have -= used;
+        Ok(frames)
+    }
+}
+
+/**
+ * Plays a Generator as a source.  The source ends when the signal does.
+ */
+pub struct SignalSource {
+    generator: Generator,
+    format: Format,
+}
+
+impl SignalSource {
+    pub fn new(generator: Generator, rate: u32) -> Self {
+        let format = Format::new(generator.channels(), rate);
+        SignalSource { generator, format }
+    }
+}
+
+impl Source for SignalSource {
+    fn format(&self) -> Format {
+        self.format
+    }
+
+    fn read(&mut self, buf: &mut [f32]) -> std::io::Result<usize> {
+        Ok(self.generator.fill_f32(buf))
+    }
+}
+
+/**
+ * Reads raw interleaved PCM in a given format, e.g., from a file.  The
+ * source ends at end of file; a partial frame there is discarded.
+ */
+pub struct RawSource<R> {
+    reader: R,
+    format: Format,
+    frames: Frames,
+}
+
+impl<R: Read + Send> RawSource<R> {
+    /**
+     * Fails with EINVAL if the format is not one we can convert.
+     */
+    pub fn new(reader: R, config: &StreamConfig) -> std::io::Result<Self> {
+        let (format, sf) = Format::from_stream(config)?;
+        Ok(RawSource {
+            reader,
+            format,
+            frames: Frames::new(sf, format.channels)?,
+        })
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+impl<R: Read + Send> Source for RawSource<R> {
+    fn format(&self) -> Format {
+        self.format
+    }
+
+    fn read(&mut self, buf: &mut [f32]) -> std::io::Result<usize> {
+        let reader = &mut self.reader;
+        self.frames.read(buf, |b| loop {
+            match reader.read(b) {
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                r => return r,
+            }
+        })
+    }
+}
+
+/**
+ * Records from a Dsp as a source.  The device is configured when the source
+ * is created, and recording starts with the first read.  The source never
+ * ends, so run it with Pipeline::run_frames().
+ */
+pub struct DspSource {
+    dsp: Dsp,
+    format: Format,
+    frames: Frames,
+}
+
+impl DspSource {
+    /**
+     * Fails with EINVAL if the format is not one we can convert, or if the
+     * device does not accept the configuration exactly.
+     */
+    pub fn new(dsp: Dsp, config: &StreamConfig) -> std::io::Result<Self> {
+        let (format, sf) = Format::from_stream(config)?;
+        configure(&dsp, config)?;
+        Ok(DspSource { dsp, format, frames: Frames::new(sf, format.channels)? })
+    }
+
+    pub fn into_inner(self) -> Dsp {
+        self.dsp
+    }
+}
+
+impl Source for DspSource {
+    fn format(&self) -> Format {
+        self.format
+    }
+
+    fn read(&mut self, buf: &mut [f32]) -> std::io::Result<usize> {
+        let dsp = &self.dsp;
+        self.frames.read(buf, |b| dsp.record(b))
+    }
+}
+
+/**
+ * Plays to a Dsp as a sink.  The device is configured when the sink is
+ * created.  Samples beyond full scale are clipped when converted to an
+ * integer format, so a Limiter at the end of the pipeline is advisable if
+ * processors add gain.
+ */
+pub struct DspSink {
+    dsp: Dsp,
+    format: Format,
+    sample: SampleFormat,
+    bytes: Vec<u8>,
+}
+
+impl DspSink {
+    /**
+     * Fails with EINVAL if the format is not one we can convert, or if the
+     * device does not accept the configuration exactly.
+     */
+    pub fn new(dsp: Dsp, config: &StreamConfig) -> std::io::Result<Self> {
+        let (format, sample) = Format::from_stream(config)?;
+        configure(&dsp, config)?;
+        Ok(DspSink { dsp, format, sample, bytes: Vec::new() })
+    }
+
+    pub fn into_inner(self) -> Dsp {
+        self.dsp
+    }
+}
+
+impl Sink for DspSink {
+    fn format(&self) -> Format {
+        self.format
+    }
+
+    fn write(&mut self, buf: &[f32]) -> std::io::Result<()> {
+        encode(self.sample, buf, &mut self.bytes);
+        self.dsp.play(&self.bytes)
+    }
+
+    /**
+     * Wait for everything written to be played.
+     */
+    fn finish(&mut self) -> std::io::Result<()> {
+        self.dsp.sync()
+    }
+}
+
+/**
+ * Writes raw interleaved PCM in a given format, e.g., to a file.
+ */
+pub struct RawSink<W> {
+    writer: W,
+    format: Format,
+    sample: SampleFormat,
+    bytes: Vec<u8>,
+}
+
+impl<W: Write + Send> RawSink<W> {
+    /**
+     * Fails with EINVAL if the format is not one we can convert.
+     */
+    pub fn new(writer: W, config: &StreamConfig) -> std::io::Result<Self> {
+        let (format, sample) = Format::from_stream(config)?;
+        Ok(RawSink { writer, format, sample, bytes: Vec::new() })
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write + Send> Sink for RawSink<W> {
+    fn format(&self) -> Format {
+        self.format
+    }
+
+    fn write(&mut self, buf: &[f32]) -> std::io::Result<()> {
+        encode(self.sample, buf, &mut self.bytes);
+        self.writer.write_all(&self.bytes)
+    }
+
+    fn finish(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+fn encode(format: SampleFormat, buf: &[f32], bytes: &mut Vec<u8>) {
+    bytes.resize(buf.len() * format.size(), 0);
+    format.encode_slice(buf, bytes);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sys::AudioFormats;
+
+    /**
+     * A source of "left" frames of a constant level.
+     */
+    struct Constant {
+        format: Format,
+        level: f32,
+        left: usize,
+    }
+
+    impl Source for Constant {
+        fn format(&self) -> Format {
+            self.format
+        }
+
+        fn read(&mut self, buf: &mut [f32]) -> std::io::Result<usize> {
+            let n = (buf.len() / self.format.channels).min(self.left);
+            buf[..n * self.format.channels].fill(self.level);
+            self.left -= n;
+            Ok(n)
+        }
+    }
+
+    #[derive(Default)]
+    struct Collect {
+        format: Option<Format>,
+        data: Vec<f32>,
+        blocks: Vec<usize>,
+        finished: bool,
+    }
+
+    impl Sink for Collect {
+        fn format(&self) -> Format {
+            self.format.unwrap()
+        }
+
+        fn write(&mut self, buf: &[f32]) -> std::io::Result<()> {
+            self.data.extend_from_slice(buf);
+            self.blocks.push(buf.len() / self.format().channels);
+            Ok(())
+        }
+
+        fn finish(&mut self) -> std::io::Result<()> {
+            self.finished = true;
+            Ok(())
+        }
+    }
+
+    /**
+     * A processor that wants a particular format, and checks it gets it.
+     */
+    struct Expect(Format);
+
+    impl Processor for Expect {
+        fn format(&self) -> Option<Format> {
+            Some(self.0)
+        }
+
+        fn process_block(&mut self, buf: &mut [f32]) {
+            assert_eq!(buf.len() % self.0.channels, 0);
+            buf.iter_mut().for_each(|v| *v *= 2.0);
+        }
+    }
+
+    fn constant(channels: usize, rate: u32, frames: usize) -> Constant {
+        Constant {
+            format: Format::new(channels, rate),
+            level: 0.25,
+            left: frames,
+        }
+    }
+
+    fn sink(channels: usize, rate: u32) -> Collect {
+        Collect {
+            format: Some(Format::new(channels, rate)),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn same_format() {
+        let mut p =
+            Pipeline::with_block_frames(constant(2, 48000, 1000), 300).unwrap();
+        let mut k = sink(2, 48000);
+
+        assert_eq!(p.run(&mut k).unwrap(), 1000);
+        assert_eq!(k.blocks, [300, 300, 300, 100]);
+        assert!(k.data.iter().all(|&v| v == 0.25));
+        assert!(k.finished);
+    }
+
+    #[test]
+    fn run_frames_limit() {
+        let mut p = Pipeline::new(constant(1, 48000, usize::MAX)).unwrap();
+        let mut k = sink(1, 48000);
+
+        assert_eq!(p.run_frames(&mut k, 2500).unwrap(), 2500);
+        assert_eq!(k.data.len(), 2500);
+    }
+
+    #[test]
+    fn sink_conversion() {
+        /*
+         * One second of 44.1 kHz mono becomes exactly one second of 48 kHz
+         * stereo, including what the resampler holds back until the end.
+         */
+        let mut p = Pipeline::new(constant(1, 44100, 44100)).unwrap();
+        let mut k = sink(2, 48000);
+
+        assert_eq!(p.run(&mut k).unwrap(), 44100);
+        assert_eq!(k.data.len(), 2 * 48000);
+        assert!(k.blocks.iter().all(|&b| b <= 1024 * 48000 / 44100 + 3));
+        let steady = &k.data[8..k.data.len() - 8];
+        assert!(steady.iter().all(|&v| (v - 0.25).abs() < 1e-6));
+    }
+
+    #[test]
+    fn chunked_conversion() {
+        /*
+         * Running in pieces gives the same result as running all at once.
+         */
+        let mut whole = sink(1, 48000);
+        Pipeline::new(constant(1, 44100, 10000))
+            .unwrap()
+            .run(&mut whole)
+            .unwrap();
+
+        let mut p = Pipeline::new(constant(1, 44100, 10000)).unwrap();
+        let mut k = sink(1, 48000);
+        while p.run_frames(&mut k, 777).unwrap() != 0 {}
+
+        assert_eq!(k.data, whole.data);
+    }
+
+    #[test]
+    fn processor_conversion() {
+        let mut p = Pipeline::new(constant(1, 48000, 4800)).unwrap();
+        p.push(Expect(Format::new(2, 48000))).unwrap();
+        assert_eq!(p.format(), Format::new(2, 48000));
+        p.push(Expect(Format::new(2, 24000))).unwrap();
+        assert_eq!(p.format(), Format::new(2, 24000));
+
+        let mut k = sink(2, 24000);
+        assert_eq!(p.run(&mut k).unwrap(), 4800);
+        assert_eq!(k.data.len(), 2 * 2400);
+        let steady = &k.data[8..k.data.len() - 8];
+        assert!(steady.iter().all(|&v| (v - 1.0).abs() < 1e-6));
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(Pipeline::with_block_frames(constant(1, 48000, 1), 0).is_err());
+        assert!(Pipeline::new(constant(0, 48000, 1)).is_err());
+        assert!(Pipeline::new(constant(1, 0, 1)).is_err());
+
+        let mut p = Pipeline::new(constant(1, 48000, 1)).unwrap();
+        assert!(p.convert(Format::new(0, 48000)).is_err());
+        assert!(p.convert(Format::new(1, 0)).is_err());
+    }
+
+    /**
+     * A reader that returns at most three bytes at a time, so that frames
+     * arrive split across reads.
+     */
+    struct Trickle(std::io::Cursor<Vec<u8>>);
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let n = buf.len().min(3);
+            self.0.read(&mut buf[..n])
+        }
+    }
+
+    #[test]
+    fn raw_round_trip() {
+        let config = StreamConfig {
+            format: AudioFormats::AFMT_S16_LE,
+            rate: 48000,
+            channels: 2,
+        };
+        let input: Vec<u8> =
+            (0..1000i16).flat_map(|i| (i * 31 - 15000).to_le_bytes()).collect();
+
+        /*
+         * The trailing partial frame is dropped.
+         */
+        let source = RawSource::new(
+            Trickle(std::io::Cursor::new(input[..1999].to_vec())),
+            &config,
+        )
+        .unwrap();
+        let mut p = Pipeline::with_block_frames(source, 64).unwrap();
+        let mut k = RawSink::new(Vec::new(), &config).unwrap();
+
+        assert_eq!(p.run(&mut k).unwrap(), 499);
+        assert_eq!(k.into_inner(), input[..1996]);
+    }
+}
@@ -0,0 +1,190 @@
+use crate::{
+    mixer::AudioInfo,
+    sys::{AudioCaps, AudioFormats},
+    Direction, Dsp, Mixer,
+};
+
+/**
+ * A description of the device we would like to use, which can be matched
+ * against the audio devices reported by the mixer.  Shadow devices are never
+ * selected, and virtual devices are skipped unless allow_virtual() is used.
+ *
+ * Devices that do not meet every requirement are rejected.  Among the rest,
+ * we prefer the system default device (if prefer_default() was used), then
+ * devices that are not already open by another process (or that support
+ * multiple opens), then devices whose channel count most closely fits what
+ * was asked for, and finally the lowest device number.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceQuery {
+    direction: Direction,
+    min_channels: Option<u32>,
+    rate: Option<u32>,
+    format: Option<AudioFormats>,
+    prefer_default: bool,
+    allow_virtual: bool,
+}
+
+impl DeviceQuery {
+    pub fn new(direction: Direction) -> Self {
+        DeviceQuery {
+            direction,
+            min_channels: None,
+            rate: None,
+            format: None,
+            prefer_default: false,
+            allow_virtual: false,
+        }
+    }
+
+    pub fn output() -> Self {
+        Self::new(Direction::Play)
+    }
+
+    pub fn input() -> Self {
+        Self::new(Direction::Record)
+    }
+
+    pub fn duplex() -> Self {
+        Self::new(Direction::Duplex)
+    }
+
+    pub fn min_channels(mut self, channels: u32) -> Self {
+        self.min_channels = Some(channels);
+        self
+    }
+
+    pub fn rate(mut self, rate: u32) -> Self {
+        self.rate = Some(rate);
+        self
+    }
+
+    pub fn format(mut self, format: AudioFormats) -> Self {
+        self.format = Some(format);
+        self
+    }
+
+    pub fn prefer_default(mut self) -> Self {
+        self.prefer_default = true;
+        self
+    }
+
+    pub fn allow_virtual(mut self) -> Self {
+        self.allow_virtual = true;
+        self
+    }
+
+    /**
+     * Whether the device meets every requirement of the query.
+     */
+    pub fn matches(&self, info: &AudioInfo) -> bool {
+        self.score(info).is_some()
+    }
+
+    /**
+     * Score the device against the query.  Returns None if the device does
+     * not meet the requirements; otherwise, a higher score is a better match.
+     */
+    pub fn score(&self, info: &AudioInfo) -> Option<u64> {
+        let caps = info.caps;
+
+        if caps.contains(AudioCaps::PCM_CAP_SHADOW) || !info.enabled {
+            return None;
+        }
+        if caps.contains(AudioCaps::PCM_CAP_VIRTUAL) && !self.allow_virtual {
+            return None;
+        }
+
+        let (need, formats) = match self.direction {
+            Direction::Play => (AudioCaps::PCM_CAP_OUTPUT, info.oformats),
+            Direction::Record => (AudioCaps::PCM_CAP_INPUT, info.iformats),
+            Direction::Duplex => (
+                AudioCaps::PCM_CAP_OUTPUT
+                    | AudioCaps::PCM_CAP_INPUT
+                    | AudioCaps::PCM_CAP_DUPLEX,
+                info.oformats & info.iformats,
+            ),
+        };
+        if !caps.contains(need) {
+            return None;
+        }
+
+        if let Some(f) = self.format {
+            if !formats.contains(f) {
+                return None;
+            }
+        }
+        if let Some(r) = self.rate {
+            if !info.supports_rate(r) {
+                return None;
+            }
+        }
+        if let Some(c) = self.min_channels {
+            if info.max_channels < c {
+                return None;
+            }
+        }
+
+        /*
+         * Assemble the score from fields of decreasing importance, so that a
+         * lesser preference can only break ties in a greater one.
+         */
+        let is_default =
+            self.prefer_default && caps.contains(AudioCaps::PCM_CAP_DEFAULT);
+        let available =
+            info.busy.is_empty() || caps.contains(AudioCaps::PCM_CAP_MULTI);
+        let want = self.min_channels.unwrap_or(1);
+        let excess = info.max_channels.saturating_sub(want).min(0xFFFF);
+
+        Some(
+            (u64::from(is_default) << 50)
+                | (u64::from(available) << 49)
+                | (u64::from(0xFFFF - excess) << 32)
+                | u64::from(u32::MAX - info.dev),
+        )
+    }
+
+    /**
+     * Return every matching device in the system, best match first.
+     */
+    pub fn candidates(&self, mixer: &Mixer) -> std::io::Result<Vec<AudioInfo>> {
+        let mut scored = mixer
+            .audio_devices()?
+            .filter_map(|info| match info {
+                Ok(info) => self.score(&info).map(|s| Ok((s, info))),
+                Err(e) => Some(Err(e)),
+            })
+            .collect::<std::io::Result<Vec<_>>>()?;
+
+        scored.sort_by_key(|(score, _)| std::cmp::Reverse(*score));
+        Ok(scored.into_iter().map(|(_, info)| info).collect())
+    }
+
+    pub fn select(&self, mixer: &Mixer) -> std::io::Result<Option<AudioInfo>> {
+        Ok(self.candidates(mixer)?.into_iter().next())
+    }
+
+    /**
+     * Open the best matching device.  Any format, rate, and channel count
+     * given in the query are applied to the device before it is returned.
+     * Fails with ENOENT if no device matches.
+     */
+    pub fn open(&self, mixer: &Mixer) -> std::io::Result<(Dsp, AudioInfo)> {
+        let info = self
+            .select(mixer)?
+            .ok_or_else(|| std::io::Error::from_raw_os_error(libc::ENOENT))?;
+
+        let dsp = Dsp::open(&info.devnode, self.direction)?;
+        if let Some(f) = self.format {
+            dsp.format_set(f)?;
+        }
+        if let Some(c) = self.min_channels {
+            dsp.channels_set(c)?;
+        }
+        if let Some(r) = self.rate {
+            dsp.speed_set(r)?;
+        }
+
+        Ok((dsp, info))
+    }
+}
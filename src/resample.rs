@@ -0,0 +1,235 @@
+/*
+ * Sample rate conversion for interleaved floating point frames.
+ */
+
+/**
+ * A streaming sample rate converter using 4-point cubic Hermite
+ * interpolation.  It is cheap enough to run many at once on a real-time
+ * thread and does not allocate after creation, but it does no low-pass
+ * filtering of its own, so content above the output Nyquist frequency will
+ * alias when converting to a much lower rate.
+ *
+ * Each output frame depends on two input frames beyond it, so the last two
+ * input frames remain in the converter until more input arrives.
+ */
+#[derive(Debug, Clone)]
+pub struct Resampler {
+    channels: usize,
+    from: u32,
+    to: u32,
+    /*
+     * The input position of the next output frame, relative to the second
+     * of the four frames in "hist", in units of 1/to of an input frame.
+     * Keeping it as an integer means the output never drifts from the exact
+     * ratio of the rates, however long the stream.
+     */
+    t: u64,
+    hist: Vec<f32>,
+}
+
+impl Resampler {
+    /**
+     * Create a converter from rate "from" to rate "to".  Fails with EINVAL if
+     * either rate is zero.
+     */
+    pub fn new(channels: usize, from: u32, to: u32) -> std::io::Result<Self> {
+        if from == 0 || to == 0 {
+            return Err(std::io::Error::from_raw_os_error(libc::EINVAL));
+        }
+
+        Ok(Resampler {
+            channels,
+            from,
+            to,
+            t: 3 * u64::from(to),
+            hist: vec![0.0; 4 * channels],
+        })
+    }
+
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+
+    pub fn from_rate(&self) -> u32 {
+        self.from
+    }
+
+    pub fn to_rate(&self) -> u32 {
+        self.to
+    }
+
+    /**
+     * Whether the rates are the same, in which case process() just copies.
+     */
+    pub fn is_passthrough(&self) -> bool {
+        self.from == self.to
+    }
+
+    /**
+     * The number of input frames held back by process() until more input
+     * arrives.  Following the end of the input with this many frames of
+     * silence brings out the rest of it.
+     */
+    pub fn delay(&self) -> usize {
+        if self.is_passthrough() {
+            0
+        } else {
+            2
+        }
+    }
+
+    /**
+     * Convert as much of "input" as possible into "output".  Returns the
+     * number of input frames consumed and output frames produced; conversion
+     * stops when either buffer runs out.  Partial frames are ignored.
+     */
+    pub fn process(
+        &mut self,
+        input: &[f32],
+        output: &mut [f32],
+    ) -> (usize, usize) {
+        let ch = self.channels;
+        if ch == 0 {
+            return (0, 0);
+        }
+
+        if self.is_passthrough() {
+            let n = (input.len() / ch).min(output.len() / ch);
+            output[..n * ch].copy_from_slice(&input[..n * ch]);
+            return (n, n);
+        }
+
+        let mut inputs = input.chunks_exact(ch);
+        let mut used = 0;
+        let mut made = 0;
+        let to = u64::from(self.to);
+
+        for out in output.chunks_exact_mut(ch) {
+            while self.t >= to {
+                let Some(frame) = inputs.next() else {
+                    return (used, made);
+                };
+                self.hist.copy_within(ch.., 0);
+                self.hist[3 * ch..].copy_from_slice(frame);
+                self.t -= to;
+                used += 1;
+            }
+
+            let t = (self.t as f64 / to as f64) as f32;
+            for (c, o) in out.iter_mut().enumerate() {
+                let xm1 = self.hist[c];
+                let x0 = self.hist[ch + c];
+                let x1 = self.hist[2 * ch + c];
+                let x2 = self.hist[3 * ch + c];
+
+                let c1 = 0.5 * (x1 - xm1);
+                let c2 = xm1 - 2.5 * x0 + 2.0 * x1 - 0.5 * x2;
+                let c3 = 0.5 * (x2 - xm1) + 1.5 * (x0 - x1);
+                *o = ((c3 * t + c2) * t + c1) * t + x0;
+            }
+
+            self.t += u64::from(self.from);
+            made += 1;
+        }
+
+        (used, made)
+    }
+
+    /**
+     * Forget any buffered input, as after a discontinuity in the audio.
+     */
+    pub fn reset(&mut self) {
+        self.hist.fill(0.0);
+        self.t = 3 * u64::from(self.to);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /**
+     * Convert "input" in blocks of "block" input frames, with room for
+     * "out_block" output frames at a time, and return the output.
+     */
+    fn convert(
+        r: &mut Resampler,
+        input: &[f32],
+        block: usize,
+        out_block: usize,
+    ) -> Vec<f32> {
+        let ch = r.channels();
+        let mut out = Vec::new();
+        let mut buf = vec![0.0; out_block * ch];
+
+        for chunk in input.chunks(block * ch) {
+            let mut chunk = chunk;
+            loop {
+                let (used, made) = r.process(chunk, &mut buf);
+                out.extend_from_slice(&buf[..made * ch]);
+                chunk = &chunk[used * ch..];
+                if made < out_block {
+                    break;
+                }
+            }
+            assert!(chunk.is_empty());
+        }
+
+        out
+    }
+
+    #[test]
+    fn frame_counts() {
+        for (from, to) in [(44100, 48000), (48000, 44100), (8000, 48000)] {
+            let mut r = Resampler::new(2, from, to).unwrap();
+            let input = vec![0.25; 2 * from as usize];
+            let out = convert(&mut r, &input, 100, 64);
+
+            /*
+             * One second in gives one second out, less what is held back.
+             */
+            let frames = out.len() / 2;
+            let expect =
+                (to as usize) - r.delay() * to as usize / from as usize;
+            assert!(frames.abs_diff(expect) <= 1, "{from}->{to}: {frames}");
+
+            /*
+             * Pushing silence through brings the rest out, for exactly one
+             * second in all.
+             */
+            let silence = vec![0.0; 2 * r.delay()];
+            let tail = convert(&mut r, &silence, 100, 64);
+            let total = frames + tail.len() / 2;
+            assert_eq!(total, to as usize, "{from}->{to}");
+        }
+    }
+
+    #[test]
+    fn dc() {
+        let mut r = Resampler::new(1, 44100, 48000).unwrap();
+        let out = convert(&mut r, &[0.5; 4410], 441, 100);
+
+        /*
+         * The first few frames ramp up from the initial silence.
+         */
+        assert!(out[2..].iter().all(|&v| (v - 0.5).abs() < 1e-6));
+    }
+
+    #[test]
+    fn passthrough() {
+        let mut r = Resampler::new(2, 48000, 48000).unwrap();
+        assert!(r.is_passthrough());
+        assert_eq!(r.delay(), 0);
+
+        let input: Vec<f32> = (0..20).map(|i| i as f32).collect();
+        let mut out = [0.0; 12];
+        assert_eq!(r.process(&input, &mut out), (6, 6));
+        assert_eq!(out[..], input[..12]);
+    }
+
+    #[test]
+    fn zero_rate() {
+        assert!(Resampler::new(2, 0, 48000).is_err());
+        assert!(Resampler::new(2, 48000, 0).is_err());
+    }
+}
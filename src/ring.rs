@@ -0,0 +1,471 @@
+use std::{
+    cell::UnsafeCell,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use crate::Dsp;
+
+/**
+ * Create a single-producer, single-consumer ring buffer that holds up to
+ * "frames" frames of "frame_size" bytes each; e.g., the value returned by
+ * StreamConfig::frame_size() for the negotiated format.  Neither end ever
+ * blocks, takes a lock, or allocates, so either may be used from a real-time
+ * I/O thread.
+ */
+pub fn ring_buffer(
+    frames: usize,
+    frame_size: usize,
+) -> std::io::Result<(RingProducer, RingConsumer)> {
+    let bytes = frames
+        .checked_mul(frame_size)
+        .filter(|&b| b > 0 && frames.checked_mul(2).is_some())
+        .ok_or_else(|| std::io::Error::from_raw_os_error(libc::EINVAL))?;
+
+    let inner = Arc::new(Inner {
+        buf: (0..bytes).map(|_| UnsafeCell::new(0)).collect(),
+        frames,
+        frame_size,
+        head: AtomicUsize::new(0),
+        tail: AtomicUsize::new(0),
+        overflows: AtomicU64::new(0),
+        underflows: AtomicU64::new(0),
+    });
+
+    Ok((RingProducer { inner: Arc::clone(&inner) }, RingConsumer { inner }))
+}
+
+/**
+ * A snapshot of the fill level of a ring buffer.  The overflow count is the
+ * number of frames the producer offered that did not fit; the underflow count
+ * is the number of frames the consumer asked for that were not yet available.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RingStats {
+    pub capacity: usize,
+    pub filled: usize,
+    pub overflows: u64,
+    pub underflows: u64,
+}
+
+impl RingStats {
+    pub fn free(&self) -> usize {
+        self.capacity - self.filled
+    }
+}
+
+struct Inner {
+    buf: Box<[UnsafeCell<u8>]>,
+    frames: usize,
+    frame_size: usize,
+
+    /*
+     * Frame counts modulo twice the capacity: "head" is advanced only by the
+     * producer once it has written frames, and "tail" only by the consumer
+     * once it has read them.  The difference is the number of frames in the
+     * buffer.  Wrapping at twice the capacity, rather than letting the counts
+     * overflow, keeps the buffer position (the count modulo the capacity)
+     * continuous for any capacity, while still distinguishing full from
+     * empty.
+     */
+    head: AtomicUsize,
+    tail: AtomicUsize,
+
+    overflows: AtomicU64,
+    underflows: AtomicU64,
+}
+
+/*
+ * The producer and consumer only ever touch disjoint regions of the buffer,
+ * as arbitrated by the head and tail counters.
+ */
+unsafe impl Sync for Inner {}
+
+impl Inner {
+    fn stats(&self) -> RingStats {
+        let tail = self.tail.load(Ordering::Acquire);
+        let head = self.head.load(Ordering::Acquire);
+
+        RingStats {
+            capacity: self.frames,
+            filled: self.filled(head, tail),
+            overflows: self.overflows.load(Ordering::Relaxed),
+            underflows: self.underflows.load(Ordering::Relaxed),
+        }
+    }
+
+    fn filled(&self, head: usize, tail: usize) -> usize {
+        if head >= tail {
+            head - tail
+        } else {
+            head + (2 * self.frames - tail)
+        }
+    }
+
+    /**
+     * Advance a head or tail count by "n" frames, where "n" is at most the
+     * capacity.
+     */
+    fn advance(&self, pos: usize, n: usize) -> usize {
+        let room = 2 * self.frames - pos;
+        if n >= room {
+            n - room
+        } else {
+            pos + n
+        }
+    }
+
+    /**
+     * Return the byte offsets and lengths of the (up to two) contiguous
+     * regions that make up "count" frames starting at frame "pos".
+     */
+    fn regions(&self, pos: usize, count: usize) -> [(usize, usize); 2] {
+        let start = pos % self.frames;
+        let first = count.min(self.frames - start);
+
+        [
+            (start * self.frame_size, first * self.frame_size),
+            (0, (count - first) * self.frame_size),
+        ]
+    }
+
+    fn ptr(&self) -> *mut u8 {
+        UnsafeCell::raw_get(self.buf.as_ptr())
+    }
+}
+
+pub struct RingProducer {
+    inner: Arc<Inner>,
+}
+
+impl RingProducer {
+    pub fn frame_size(&self) -> usize {
+        self.inner.frame_size
+    }
+
+    pub fn stats(&self) -> RingStats {
+        self.inner.stats()
+    }
+
+    /**
+     * Copy as many whole frames from "data" as will fit, returning the number
+     * of frames written.  Any trailing partial frame is ignored.
+     */
+    pub fn write(&mut self, data: &[u8]) -> usize {
+        let i = &*self.inner;
+
+        let head = i.head.load(Ordering::Relaxed);
+        let tail = i.tail.load(Ordering::Acquire);
+        let free = i.frames - i.filled(head, tail);
+
+        let offered = data.len() / i.frame_size;
+        let count = offered.min(free);
+        if count < offered {
+            i.overflows.fetch_add((offered - count) as u64, Ordering::Relaxed);
+        }
+
+        let mut src = 0;
+        for (off, len) in i.regions(head, count) {
+            unsafe {
+                std::ptr::copy_nonoverlapping(
+                    data[src..src + len].as_ptr(),
+                    i.ptr().add(off),
+                    len,
+                );
+            }
+            src += len;
+        }
+
+        i.head.store(i.advance(head, count), Ordering::Release);
+        count
+    }
+}
+
+pub struct RingConsumer {
+    inner: Arc<Inner>,
+}
+
+impl RingConsumer {
+    pub fn frame_size(&self) -> usize {
+        self.inner.frame_size
+    }
+
+    pub fn stats(&self) -> RingStats {
+        self.inner.stats()
+    }
+
+    /**
+     * Copy as many whole frames into "out" as are available, returning the
+     * number of frames read.
+     */
+    pub fn read(&mut self, out: &mut [u8]) -> usize {
+        let wanted = out.len() / self.inner.frame_size;
+
+        self.consume(wanted, |data, dst| {
+            out[dst..dst + data.len()].copy_from_slice(data);
+            Ok(data.len())
+        })
+        .unwrap()
+    }
+
+    /**
+     * Fill "out" completely, as for a Stream callback.  If fewer frames are
+     * available than requested, the remainder is filled with zero bytes
+     * (silence for signed and floating point formats) and the shortfall is
+     * counted as an underflow.
+     */
+    pub fn fill(&mut self, out: &mut [u8]) {
+        let fs = self.inner.frame_size;
+        let wanted = out.len() / fs;
+
+        let got = self.read(out);
+        if got < wanted {
+            let short = (wanted - got) as u64;
+            self.inner.underflows.fetch_add(short, Ordering::Relaxed);
+        }
+        out[got * fs..].fill(0);
+    }
+
+    /**
+     * Write as many frames to the device as are buffered and will fit in the
+     * device play buffer without blocking, according to space_output().
+     * Returns the number of frames played.  Only frames the device actually
+     * accepted are released; if a write ends part way through a frame, we
+     * wait for room and write the rest of that frame before returning.
+     */
+    pub fn play_into(&mut self, dsp: &Dsp) -> std::io::Result<usize> {
+        let fs = self.inner.frame_size;
+        let space = dsp.space_output()?;
+        let room = usize::try_from(space.bytes).unwrap_or(0) / fs;
+
+        self.consume(room, |data, _| {
+            let n = match dsp.play_some(data) {
+                Ok(n) => n,
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => 0,
+                Err(e) => return Err(e),
+            };
+            let partial = n % fs;
+            if partial == 0 {
+                return Ok(n);
+            }
+
+            /*
+             * On a non-blocking descriptor, play() fails at once if there is
+             * no room yet, so wait for some first.  If the frame still cannot
+             * be finished, release the whole frames the device did take, so
+             * that they are not played twice; the error will come up again
+             * on the next call.
+             */
+            let rest = &data[n..n + fs - partial];
+            match dsp.wait_writable(None).and_then(|_| dsp.play(rest)) {
+                Ok(()) => Ok(n + fs - partial),
+                Err(_) => Ok(n - partial),
+            }
+        })
+    }
+
+    /**
+     * Pass up to "wanted" frames to "f", one contiguous region at a time,
+     * along with the byte offset of that region in the overall read.  "f"
+     * returns how many bytes of the region it accepted, which must be a whole
+     * number of frames; if that is less than the whole region, we stop there.
+     * Frames are released back to the producer once "f" has accepted them;
+     * if "f" fails, the frames of that region remain in the buffer.
+     */
+    fn consume<F>(&mut self, wanted: usize, mut f: F) -> std::io::Result<usize>
+    where
+        F: FnMut(&[u8], usize) -> std::io::Result<usize>,
+    {
+        let i = &*self.inner;
+
+        let tail = i.tail.load(Ordering::Relaxed);
+        let head = i.head.load(Ordering::Acquire);
+        let count = wanted.min(i.filled(head, tail));
+
+        let mut dst = 0;
+        for (off, len) in i.regions(tail, count) {
+            if len == 0 {
+                continue;
+            }
+            let data =
+                unsafe { std::slice::from_raw_parts(i.ptr().add(off), len) };
+            match f(data, dst) {
+                Ok(n) => {
+                    dst += n;
+                    if n < len {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    let done = dst / i.frame_size;
+                    i.tail.store(i.advance(tail, done), Ordering::Release);
+                    return Err(e);
+                }
+            }
+        }
+
+        let done = dst / i.frame_size;
+        i.tail.store(i.advance(tail, done), Ordering::Release);
+        Ok(done)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frames(range: std::ops::Range<u16>) -> Vec<u8> {
+        range.flat_map(u16::to_le_bytes).collect()
+    }
+
+    #[test]
+    fn rejects_empty() {
+        assert!(ring_buffer(0, 2).is_err());
+        assert!(ring_buffer(4, 0).is_err());
+        assert!(ring_buffer(usize::MAX, 1).is_err());
+    }
+
+    #[test]
+    fn wraparound() {
+        /*
+         * A capacity that does not divide the counter range evenly, with
+         * reads and writes that straddle the end of the buffer.
+         */
+        let (mut p, mut c) = ring_buffer(5, 2).unwrap();
+        let mut out = [0u8; 6];
+        let mut next = 0;
+
+        for _ in 0..100 {
+            assert_eq!(p.write(&frames(next..next + 3)), 3);
+            assert_eq!(c.stats().filled, 3);
+            assert_eq!(c.read(&mut out), 3);
+            assert_eq!(out[..], frames(next..next + 3)[..]);
+            next += 3;
+        }
+
+        let s = c.stats();
+        assert_eq!((s.filled, s.overflows, s.underflows), (0, 0, 0));
+    }
+
+    #[test]
+    fn full_and_overflow() {
+        let (mut p, mut c) = ring_buffer(3, 2).unwrap();
+
+        assert_eq!(p.write(&frames(0..2)), 2);
+        assert_eq!(p.write(&frames(2..5)), 1);
+        let s = p.stats();
+        assert_eq!((s.filled, s.free(), s.overflows), (3, 0, 2));
+
+        let mut out = [0u8; 8];
+        assert_eq!(c.read(&mut out), 3);
+        assert_eq!(out[..6], frames(0..3)[..]);
+        assert_eq!(c.read(&mut out), 0);
+    }
+
+    #[test]
+    fn partial_frames_ignored() {
+        let (mut p, mut c) = ring_buffer(4, 2).unwrap();
+
+        assert_eq!(p.write(&[1, 2, 3]), 1);
+        let mut out = [0u8; 3];
+        assert_eq!(c.read(&mut out), 1);
+        assert_eq!(out, [1, 2, 0]);
+    }
+
+    #[test]
+    fn fill_underflow() {
+        let (mut p, mut c) = ring_buffer(4, 2).unwrap();
+
+        p.write(&frames(1..3));
+        let mut out = [0xFFu8; 8];
+        c.fill(&mut out);
+        assert_eq!(out[..4], frames(1..3)[..]);
+        assert_eq!(out[4..], [0; 4]);
+        assert_eq!(c.stats().underflows, 2);
+    }
+
+    #[test]
+    fn partial_consume() {
+        let (mut p, mut c) = ring_buffer(5, 2).unwrap();
+
+        /*
+         * Move the tail near the end of the buffer, so that the next four
+         * frames are split into regions of two frames each.
+         */
+        p.write(&frames(0..3));
+        c.read(&mut [0u8; 6]);
+        p.write(&frames(3..7));
+
+        /*
+         * Accept only the first frame of the first region.
+         */
+        let mut seen = Vec::new();
+        let n = c
+            .consume(4, |data, dst| {
+                assert_eq!(dst, 0);
+                seen.extend_from_slice(&data[..2]);
+                Ok(2)
+            })
+            .unwrap();
+        assert_eq!(n, 1);
+        assert_eq!(seen, frames(3..4));
+        assert_eq!(c.stats().filled, 3);
+
+        /*
+         * A failure releases only the frames accepted before it.
+         */
+        let err = c
+            .consume(4, |data, dst| {
+                if dst == 0 {
+                    Ok(data.len())
+                } else {
+                    Err(std::io::Error::from_raw_os_error(libc::EIO))
+                }
+            })
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EIO));
+        assert_eq!(c.stats().filled, 2);
+
+        let mut out = [0u8; 4];
+        assert_eq!(c.read(&mut out), 2);
+        assert_eq!(out[..], frames(5..7)[..]);
+    }
+
+    #[test]
+    fn threads() {
+        /*
+         * Stream a counting sequence through a small buffer from one thread
+         * to another, in uneven chunks, and check that it arrives intact.
+         */
+        const COUNT: u16 = 50_000;
+        let (mut p, mut c) = ring_buffer(7, 2).unwrap();
+
+        let producer = std::thread::spawn(move || {
+            let data = frames(0..COUNT);
+            let mut sent = 0;
+            let mut chunk = 1;
+            while sent < data.len() {
+                let end = data.len().min(sent + chunk * 2);
+                sent += p.write(&data[sent..end]) * 2;
+                chunk = chunk % 9 + 1;
+                std::thread::yield_now();
+            }
+        });
+
+        let mut got = Vec::with_capacity(usize::from(COUNT) * 2);
+        let mut out = [0u8; 10];
+        let mut chunk = 1;
+        while got.len() < usize::from(COUNT) * 2 {
+            let n = c.read(&mut out[..chunk * 2]);
+            got.extend_from_slice(&out[..n * 2]);
+            chunk = chunk % 5 + 1;
+            std::thread::yield_now();
+        }
+
+        producer.join().unwrap();
+        assert_eq!(got, frames(0..COUNT));
+        assert_eq!(c.stats().filled, 0);
+    }
+}
@@ -0,0 +1,207 @@
+use crate::sys::AudioFormats;
+
+/**
+ * The linear PCM sample formats we know how to convert to and from floating
+ * point.  Samples are represented as f32 values nominally in the range
+ * [-1.0, 1.0]; values outside that range are clipped when encoding to an
+ * integer format.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SampleFormat {
+    U8,
+    S8,
+    S16LE,
+    S16BE,
+    /**
+     * 24-bit samples, sign extended in the low three bytes of a 32-bit
+     * little-endian word.
+     */
+    S24LE,
+    S24BE,
+    /**
+     * 24-bit little-endian samples packed into three bytes.
+     */
+    S24Packed,
+    S32LE,
+    S32BE,
+    /**
+     * Native-endian IEEE 754 single precision floating point.
+     */
+    Float,
+}
+
+impl SampleFormat {
+    pub fn from_audio_format(format: AudioFormats) -> Option<Self> {
+        Some(match format {
+            AudioFormats::AFMT_U8 => Self::U8,
+            AudioFormats::AFMT_S8 => Self::S8,
+            AudioFormats::AFMT_S16_LE => Self::S16LE,
+            AudioFormats::AFMT_S16_BE => Self::S16BE,
+            AudioFormats::AFMT_S24_LE => Self::S24LE,
+            AudioFormats::AFMT_S24_BE => Self::S24BE,
+            AudioFormats::AFMT_S24_PACKED => Self::S24Packed,
+            AudioFormats::AFMT_S32_LE => Self::S32LE,
+            AudioFormats::AFMT_S32_BE => Self::S32BE,
+            AudioFormats::AFMT_FLOAT => Self::Float,
+            _ => return None,
+        })
+    }
+
+    pub fn audio_format(&self) -> AudioFormats {
+        match self {
+            Self::U8 => AudioFormats::AFMT_U8,
+            Self::S8 => AudioFormats::AFMT_S8,
+            Self::S16LE => AudioFormats::AFMT_S16_LE,
+            Self::S16BE => AudioFormats::AFMT_S16_BE,
+            Self::S24LE => AudioFormats::AFMT_S24_LE,
+            Self::S24BE => AudioFormats::AFMT_S24_BE,
+            Self::S24Packed => AudioFormats::AFMT_S24_PACKED,
+            Self::S32LE => AudioFormats::AFMT_S32_LE,
+            Self::S32BE => AudioFormats::AFMT_S32_BE,
+            Self::Float => AudioFormats::AFMT_FLOAT,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        match self {
+            Self::U8 | Self::S8 => 1,
+            Self::S16LE | Self::S16BE => 2,
+            Self::S24Packed => 3,
+            Self::S24LE | Self::S24BE | Self::S32LE | Self::S32BE => 4,
+            Self::Float => 4,
+        }
+    }
+
+    /**
+     * The byte value that fills a buffer of silence in this format.
+     */
+    pub fn silence(&self) -> u8 {
+        match self {
+            Self::U8 => 0x80,
+            _ => 0,
+        }
+    }
+
+    /**
+     * Decode one sample from the start of "b", which must be at least size()
+     * bytes long.
+     */
+    pub fn decode(&self, b: &[u8]) -> f32 {
+        match self {
+            Self::U8 => (b[0] as f32 - 128.0) / 128.0,
+            Self::S8 => b[0] as i8 as f32 / 128.0,
+            Self::S16LE => i16::from_le_bytes([b[0], b[1]]) as f32 / 32768.0,
+            Self::S16BE => i16::from_be_bytes([b[0], b[1]]) as f32 / 32768.0,
+            Self::S24LE => {
+                let v = i32::from_le_bytes([b[0], b[1], b[2], b[3]]);
+                ((v << 8) >> 8) as f32 / 8388608.0
+            }
+            Self::S24BE => {
+                let v = i32::from_be_bytes([b[0], b[1], b[2], b[3]]);
+                ((v << 8) >> 8) as f32 / 8388608.0
+            }
+            Self::S24Packed => {
+                let v = i32::from_le_bytes([0, b[0], b[1], b[2]]);
+                (v >> 8) as f32 / 8388608.0
+            }
+            Self::S32LE => {
+                i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f32
+                    / 2147483648.0
+            }
+            Self::S32BE => {
+                i32::from_be_bytes([b[0], b[1], b[2], b[3]]) as f32
+                    / 2147483648.0
+            }
+            Self::Float => f32::from_ne_bytes([b[0], b[1], b[2], b[3]]),
+        }
+    }
+
+    /**
+     * Encode one sample into the start of "b", which must be at least size()
+     * bytes long.
+     */
+    pub fn encode(&self, v: f32, b: &mut [u8]) {
+        /*
+         * Scale to the integer range and clip, so that a value of exactly 1.0
+         * produces the largest positive sample rather than wrapping.
+         */
+        fn scale(v: f32, max: f64) -> i32 {
+            (v as f64 * (max + 1.0)).round().clamp(-max - 1.0, max) as i32
+        }
+
+        match self {
+            Self::U8 => b[0] = (scale(v, 127.0) + 128) as u8,
+            Self::S8 => b[0] = scale(v, 127.0) as i8 as u8,
+            Self::S16LE => b[..2]
+                .copy_from_slice(&(scale(v, 32767.0) as i16).to_le_bytes()),
+            Self::S16BE => b[..2]
+                .copy_from_slice(&(scale(v, 32767.0) as i16).to_be_bytes()),
+            Self::S24LE => {
+                b[..4].copy_from_slice(&scale(v, 8388607.0).to_le_bytes())
+            }
+            Self::S24BE => {
+                b[..4].copy_from_slice(&scale(v, 8388607.0).to_be_bytes())
+            }
+            Self::S24Packed => {
+                b[..3].copy_from_slice(&scale(v, 8388607.0).to_le_bytes()[..3])
+            }
+            Self::S32LE => {
+                b[..4].copy_from_slice(&scale(v, 2147483647.0).to_le_bytes())
+            }
+            Self::S32BE => {
+                b[..4].copy_from_slice(&scale(v, 2147483647.0).to_be_bytes())
+            }
+            Self::Float => b[..4].copy_from_slice(&v.to_ne_bytes()),
+        }
+    }
+
+    /**
+     * Decode as many whole samples as fit in both "input" and "output".
+     * Returns the number of samples decoded.
+     */
+    pub fn decode_slice(&self, input: &[u8], output: &mut [f32]) -> usize {
+        let mut n = 0;
+        for (b, o) in input.chunks_exact(self.size()).zip(output.iter_mut()) {
+            *o = self.decode(b);
+            n += 1;
+        }
+        n
+    }
+
+    /**
+     * Encode as many whole samples as fit in both "input" and "output".
+     * Returns the number of samples encoded.
+     */
+    pub fn encode_slice(&self, input: &[f32], output: &mut [u8]) -> usize {
+        let mut n = 0;
+        for (v, b) in input.iter().zip(output.chunks_exact_mut(self.size())) {
+            self.encode(*v, b);
+            n += 1;
+        }
+        n
+    }
+}
+
+/**
+ * Add interleaved frames with "sc" channels to frames with "dc" channels.  A
+ * mono source is copied to every output channel and a multichannel source is
+ * averaged into a mono output; otherwise channels are matched by position,
+ * and any without a counterpart are dropped or left silent.
+ */
+pub(crate) fn add_mapped(src: &[f32], sc: usize, dst: &mut [f32], dc: usize) {
+    let frames = src.chunks_exact(sc).zip(dst.chunks_exact_mut(dc));
+
+    if sc == 1 {
+        for (s, d) in frames {
+            d.iter_mut().for_each(|d| *d += s[0]);
+        }
+    } else if dc == 1 {
+        for (s, d) in frames {
+            d[0] += s.iter().sum::<f32>() / sc as f32;
+        }
+    } else {
+        for (s, d) in frames {
+            d.iter_mut().zip(s).for_each(|(d, s)| *d += s);
+        }
+    }
+}
@@ -0,0 +1,546 @@
+/*
+ * A software mixer, which lets several independent sources share one Dsp on
+ * devices that cannot be opened more than once; i.e., those without
+ * PCM_CAP_MULTI.
+ */
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use crate::{
+    dynamics::{Limiter, LimiterConfig},
+    gain::{Gain, Ramp},
+    resample::Resampler,
+    ring::{ring_buffer, RingConsumer, RingProducer, RingStats},
+    sample::{add_mapped, SampleFormat},
+    stream::{Stream, StreamConfig},
+    Dsp,
+};
+
+/*
+ * Sources are converted and mixed in blocks of at most this many frames.
+ */
+const BLOCK_FRAMES: usize = 256;
+
+/*
+ * Source gain changes are spread over this long to avoid clicks.
+ */
+const GAIN_RAMP: Duration = Duration::from_millis(20);
+
+/*
+ * How long MixerSource::write_all() sleeps while waiting for room.
+ */
+const WRITE_WAIT: Duration = Duration::from_millis(5);
+
+/*
+ * Room for this many sources is set aside up front, so that adding sources
+ * does not usually allocate on the audio thread.
+ */
+const INITIAL_SOURCES: usize = 16;
+
+/*
+ * Sources added since the last mix() call; None once the SoftMixer has been
+ * dropped.
+ */
+type Pending = Arc<Mutex<Option<Vec<Input>>>>;
+
+#[derive(Debug)]
+struct SourceShared {
+    gain_db: AtomicU64,
+    closed: AtomicBool,
+}
+
+/**
+ * The mixer's end of a source: the ring buffer the source writes into, and
+ * the buffers used to convert its audio to the output format.
+ */
+struct Input {
+    consumer: RingConsumer,
+    shared: Arc<SourceShared>,
+    format: SampleFormat,
+    channels: usize,
+    resampler: Resampler,
+    gain: Gain,
+    gain_db: f64,
+
+    bytes: Vec<u8>,
+    decoded: Vec<f32>,
+    pos: usize,
+    len: usize,
+    resampled: Vec<f32>,
+    flushed: bool,
+}
+
+impl Input {
+    /**
+     * Convert up to out.len() / out_channels frames of this source and add
+     * them to "out".  Returns the number of frames added, which is short only
+     * if the source has run dry.
+     */
+    fn render(&mut self, out: &mut [f32], out_channels: usize) -> usize {
+        let db = f64::from_bits(self.shared.gain_db.load(Ordering::Relaxed));
+        if db != self.gain_db {
+            self.gain.gain_db_set_all(db, Ramp::Linear(GAIN_RAMP));
+            self.gain_db = db;
+        }
+
+        let ch = self.channels;
+        let frames = out.len() / out_channels;
+        let mut done = 0;
+
+        while done < frames {
+            if self.pos == self.len {
+                /*
+                 * Check for the close before reading, so that an empty ring
+                 * means everything the source wrote has been seen.
+                 */
+                let closed = self.shared.closed.load(Ordering::Acquire);
+                let n = self.consumer.read(&mut self.bytes);
+                if n == 0 {
+                    if !closed || self.flushed {
+                        break;
+                    }
+
+                    /*
+                     * Push the frames still held in the resampler out with
+                     * silence before the source is retired.
+                     */
+                    let delay = self.resampler.delay();
+                    self.decoded[..delay * ch].fill(0.0);
+                    self.pos = 0;
+                    self.len = delay;
+                    self.flushed = true;
+                    continue;
+                }
+                self.format.decode_slice(
+                    &self.bytes[..n * ch * self.format.size()],
+                    &mut self.decoded,
+                );
+                self.pos = 0;
+                self.len = n;
+            }
+
+            let want = (frames - done).min(BLOCK_FRAMES);
+            let (used, made) = self.resampler.process(
+                &self.decoded[self.pos * ch..self.len * ch],
+                &mut self.resampled[..want * ch],
+            );
+            self.pos += used;
+
+            let res = &mut self.resampled[..made * ch];
+            self.gain.process_f32(res);
+            add_mapped(res, ch, &mut out[done * out_channels..], out_channels);
+            done += made;
+        }
+
+        done
+    }
+
+    /**
+     * Whether the source has been dropped and everything it wrote, including
+     * what was held in the resampler, has been mixed.
+     */
+    fn is_finished(&self) -> bool {
+        self.flushed && self.pos == self.len
+    }
+}
+
+/**
+ * Mixes any number of sources, each with its own format, rate, and channel
+ * count, into a single stream of frames in the output format.  Each source
+ * is written through a MixerSource, from any thread; the mixer converts it to
+ * the output rate and channel layout, applies the source's gain, sums the
+ * results, and passes the sum through a look-ahead limiter so that loud
+ * combinations do not clip.
+ *
+ * Mixing never blocks: a source that has not written enough audio in time
+ * simply contributes silence for the rest of that block.  New sources are
+ * picked up at the start of the next mix() call, and a source is removed once
+ * its MixerSource has been dropped and all of its audio has been mixed.
+ */
+pub struct SoftMixer {
+    format: SampleFormat,
+    channels: usize,
+    rate: u32,
+    inputs: Vec<Input>,
+    pending: Pending,
+    limiter: Limiter,
+    mix: Vec<f32>,
+}
+
+impl SoftMixer {
+    /**
+     * Create a mixer producing audio in the given format.  Fails with EINVAL
+     * if the format is not one we can convert, or the channel count or rate
+     * is zero.
+     */
+    pub fn new(config: &StreamConfig) -> std::io::Result<Self> {
+        let format = SampleFormat::from_audio_format(config.format)
+            .ok_or_else(|| std::io::Error::from_raw_os_error(libc::EINVAL))?;
+        let channels = config.channels as usize;
+        if channels == 0 || config.rate == 0 {
+            return Err(std::io::Error::from_raw_os_error(libc::EINVAL));
+        }
+
+        Ok(SoftMixer {
+            format,
+            channels,
+            rate: config.rate,
+            inputs: Vec::with_capacity(INITIAL_SOURCES),
+            pending: Arc::new(Mutex::new(Some(Vec::with_capacity(
+                INITIAL_SOURCES,
+            )))),
+            limiter: Limiter::new(
+                format,
+                channels,
+                config.rate,
+                &LimiterConfig::default(),
+            )?,
+            mix: vec![0.0; BLOCK_FRAMES * channels],
+        })
+    }
+
+    pub fn config(&self) -> StreamConfig {
+        StreamConfig {
+            format: self.format.audio_format(),
+            rate: self.rate,
+            channels: self.channels as u32,
+        }
+    }
+
+    /**
+     * Add a source; see SoftMixerControl::add_source().
+     */
+    pub fn add_source(
+        &self,
+        config: &StreamConfig,
+        buffer: Duration,
+    ) -> std::io::Result<MixerSource> {
+        self.control().add_source(config, buffer)
+    }
+
+    /**
+     * Return a handle that can add sources from another thread.
+     */
+    pub fn control(&self) -> SoftMixerControl {
+        SoftMixerControl {
+            channels: self.channels,
+            rate: self.rate,
+            pending: Arc::clone(&self.pending),
+        }
+    }
+
+    /**
+     * The number of sources currently being mixed, not counting any added
+     * since the last mix() call.
+     */
+    pub fn sources(&self) -> usize {
+        self.inputs.len()
+    }
+
+    /**
+     * Fill a buffer of interleaved frames with the next block of the mix, as
+     * for a Stream callback.  Any partial frame at the end of the buffer is
+     * left alone.
+     */
+    pub fn mix(&mut self, buf: &mut [u8]) {
+        if let Ok(mut pending) = self.pending.try_lock() {
+            if let Some(pending) = pending.as_mut() {
+                self.inputs.append(pending);
+            }
+        }
+
+        let fs = self.format.size() * self.channels;
+        for chunk in buf.chunks_mut(BLOCK_FRAMES * fs) {
+            let frames = chunk.len() / fs;
+            let mix = &mut self.mix[..frames * self.channels];
+            mix.fill(0.0);
+            for input in &mut self.inputs {
+                input.render(mix, self.channels);
+            }
+            self.limiter.encode_f32(mix, chunk);
+        }
+
+        /*
+         * The buffers of a finished source are freed here, on the audio
+         * thread.
+         */
+        self.inputs.retain(|i| !i.is_finished());
+    }
+
+    /**
+     * Start a Stream on the device that plays the mix.  Sources may still be
+     * added through a SoftMixerControl obtained beforehand.
+     */
+    pub fn play(mut self, dsp: Dsp) -> std::io::Result<Stream> {
+        let config = self.config();
+        Stream::new(dsp, config, move |buf| self.mix(buf))
+    }
+}
+
+impl Drop for SoftMixer {
+    fn drop(&mut self) {
+        /*
+         * Drop any sources not yet picked up, so that their MixerSources see
+         * that the mixer has gone, and refuse new ones.
+         */
+        if let Ok(mut pending) = self.pending.lock() {
+            pending.take();
+        }
+    }
+}
+
+/**
+ * Adds sources to a SoftMixer that is in use on another thread; e.g., once it
+ * has been handed to a Stream with play().
+ */
+#[derive(Clone)]
+pub struct SoftMixerControl {
+    channels: usize,
+    rate: u32,
+    pending: Pending,
+}
+
+impl SoftMixerControl {
+    /**
+     * Add a source with the given format, buffering up to "buffer" of its
+     * audio.  The source starts at unity gain.  Fails with EINVAL if the
+     * format is not one we can convert, or if the channel count, rate, or
+     * buffer size is zero, and with EPIPE if the SoftMixer has been dropped.
+     */
+    pub fn add_source(
+        &self,
+        config: &StreamConfig,
+        buffer: Duration,
+    ) -> std::io::Result<MixerSource> {
+        let einval = || std::io::Error::from_raw_os_error(libc::EINVAL);
+
+        let format = SampleFormat::from_audio_format(config.format)
+            .ok_or_else(einval)?;
+        let channels = config.channels as usize;
+        let frame_size = config.frame_size().ok_or_else(einval)?;
+        let frames =
+            (buffer.as_secs_f64() * f64::from(config.rate)).round() as usize;
+
+        let (producer, consumer) = ring_buffer(frames, frame_size)?;
+        let shared = Arc::new(SourceShared {
+            gain_db: AtomicU64::new(0f64.to_bits()),
+            closed: AtomicBool::new(false),
+        });
+
+        let input = Input {
+            consumer,
+            shared: Arc::clone(&shared),
+            format,
+            channels,
+            resampler: Resampler::new(channels, config.rate, self.rate)?,
+            gain: Gain::new(SampleFormat::Float, channels, self.rate),
+            gain_db: 0.0,
+            bytes: vec![0; BLOCK_FRAMES * frame_size],
+            decoded: vec![0.0; BLOCK_FRAMES * channels],
+            pos: 0,
+            len: 0,
+            resampled: vec![0.0; BLOCK_FRAMES * channels],
+            flushed: false,
+        };
+        self.pending
+            .lock()
+            .unwrap()
+            .as_mut()
+            .ok_or_else(|| std::io::Error::from_raw_os_error(libc::EPIPE))?
+            .push(input);
+
+        Ok(MixerSource { producer, shared, config: *config })
+    }
+
+    /**
+     * The number of output channels, which sources are mapped onto.
+     */
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+
+    pub fn rate(&self) -> u32 {
+        self.rate
+    }
+}
+
+/**
+ * One source feeding a SoftMixer.  Audio is written in the source's own
+ * format; dropping the MixerSource lets the mixer play out what has been
+ * written and then remove the source.
+ */
+pub struct MixerSource {
+    producer: RingProducer,
+    shared: Arc<SourceShared>,
+    config: StreamConfig,
+}
+
+impl MixerSource {
+    pub fn config(&self) -> StreamConfig {
+        self.config
+    }
+
+    pub fn frame_size(&self) -> usize {
+        self.producer.frame_size()
+    }
+
+    pub fn stats(&self) -> RingStats {
+        self.producer.stats()
+    }
+
+    /**
+     * Queue as many whole frames from "data" as will fit without blocking,
+     * returning the number of frames written.
+     */
+    pub fn write(&mut self, data: &[u8]) -> usize {
+        self.producer.write(data)
+    }
+
+    /**
+     * Whether the SoftMixer this source feeds has been dropped, along with
+     * the Stream playing it, if any.  Nothing written from then on is heard.
+     */
+    pub fn is_orphaned(&self) -> bool {
+        /*
+         * The mixer's Input holds the only other reference.
+         */
+        Arc::strong_count(&self.shared) == 1
+    }
+
+    /**
+     * Queue all of the whole frames in "data", sleeping while the buffer is
+     * full.  This is for threads other than the audio thread.  Fails with
+     * EPIPE if the SoftMixer is dropped before everything has been queued.
+     */
+    pub fn write_all(&mut self, data: &[u8]) -> std::io::Result<()> {
+        let fs = self.producer.frame_size();
+        let mut data = &data[..data.len() - data.len() % fs];
+
+        while !data.is_empty() {
+            if self.is_orphaned() {
+                return Err(std::io::Error::from_raw_os_error(libc::EPIPE));
+            }
+
+            let room = self.producer.stats().free();
+            if room == 0 {
+                std::thread::sleep(WRITE_WAIT);
+                continue;
+            }
+            let n = room.min(data.len() / fs);
+            let done = self.producer.write(&data[..n * fs]);
+            data = &data[done * fs..];
+        }
+
+        Ok(())
+    }
+
+    /**
+     * The gain applied to this source before mixing, in dB.
+     */
+    pub fn gain_db(&self) -> f64 {
+        f64::from_bits(self.shared.gain_db.load(Ordering::Relaxed))
+    }
+
+    /**
+     * Change the gain of this source.  The change is ramped over a short time
+     * to avoid clicks.
+     */
+    pub fn gain_db_set(&self, db: f64) {
+        self.shared.gain_db.store(db.to_bits(), Ordering::Relaxed);
+    }
+}
+
+impl Drop for MixerSource {
+    fn drop(&mut self) {
+        self.shared.closed.store(true, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sys::AudioFormats;
+
+    fn config(rate: u32, channels: u32) -> StreamConfig {
+        StreamConfig { format: AudioFormats::AFMT_S16_LE, rate, channels }
+    }
+
+    fn samples(buf: &[u8]) -> Vec<i16> {
+        buf.chunks_exact(2).map(|s| i16::from_le_bytes([s[0], s[1]])).collect()
+    }
+
+    #[test]
+    fn resampled_source_plays_out() {
+        let mut mixer = SoftMixer::new(&config(48000, 2)).unwrap();
+        let mut source = mixer
+            .add_source(&config(44100, 1), Duration::from_millis(100))
+            .unwrap();
+
+        /*
+         * 10 ms of a constant level at 44.1 kHz should come out as 10 ms at
+         * 48 kHz, on both channels, once the source is dropped.
+         */
+        let data: Vec<u8> =
+            (0..441).flat_map(|_| 8000i16.to_le_bytes()).collect();
+        assert_eq!(source.write(&data), 441);
+        drop(source);
+
+        let mut buf = vec![0u8; 4 * 1024];
+        mixer.mix(&mut buf);
+        let out = samples(&buf);
+        let sounding = out.iter().filter(|&&s| s != 0).count();
+        assert_eq!(sounding, 2 * 480);
+        assert!(out.chunks(2).all(|f| f[0] == f[1]));
+        assert_eq!(mixer.sources(), 0);
+    }
+
+    #[test]
+    fn sources_sum() {
+        let mut mixer = SoftMixer::new(&config(48000, 1)).unwrap();
+        let a = mixer.add_source(&config(48000, 1), Duration::from_millis(10));
+        let b = mixer.add_source(&config(48000, 1), Duration::from_millis(10));
+        let (mut a, mut b) = (a.unwrap(), b.unwrap());
+
+        a.write(&1000i16.to_le_bytes().repeat(100));
+        b.write(&2000i16.to_le_bytes().repeat(100));
+
+        let mut buf = vec![0u8; 2 * 600];
+        mixer.mix(&mut buf);
+        assert_eq!(mixer.sources(), 2);
+
+        /*
+         * The limiter delays the mix but leaves quiet audio alone.
+         */
+        let out = samples(&buf);
+        let sum = out.iter().filter(|&&s| s != 0).collect::<Vec<_>>();
+        assert_eq!(sum.len(), 100);
+        assert!(sum.iter().all(|&&s| (s - 3000).abs() <= 1), "{sum:?}");
+    }
+
+    #[test]
+    fn write_all_after_mixer_dropped() {
+        let mixer = SoftMixer::new(&config(48000, 1)).unwrap();
+        let control = mixer.control();
+        let mut source = mixer
+            .add_source(&config(48000, 1), Duration::from_millis(10))
+            .unwrap();
+        assert!(!source.is_orphaned());
+
+        drop(mixer);
+        assert!(source.is_orphaned());
+        let err = source.write_all(&[0u8; 48000 * 2]).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EPIPE));
+
+        let err = control
+            .add_source(&config(48000, 1), Duration::from_millis(10))
+            .err()
+            .unwrap();
+        assert_eq!(err.raw_os_error(), Some(libc::EPIPE));
+    }
+}
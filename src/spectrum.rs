@@ -0,0 +1,503 @@
+use std::{f64::consts::TAU, time::Duration};
+
+use crate::{
+    generator::{Generator, Oscillator},
+    latency,
+    sample::SampleFormat,
+    sys, Dsp, StreamConfig,
+};
+
+/**
+ * The window applied to each block of samples before it is transformed.
+ * Windows with a wider main lobe leak less energy into distant bins.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Window {
+    Rectangular,
+    Hann,
+    /**
+     * The 4-term Blackman-Harris window, whose sidelobes are more than 90 dB
+     * down; a good choice for distortion measurements.
+     */
+    BlackmanHarris,
+    /**
+     * A flat top window, which reads the amplitude of a sine accurately
+     * wherever it falls between bins, at the cost of frequency resolution.
+     */
+    FlatTop,
+}
+
+impl Window {
+    fn terms(&self) -> &'static [f64] {
+        match self {
+            Window::Rectangular => &[1.0],
+            Window::Hann => &[0.5, 0.5],
+            Window::BlackmanHarris => &[0.35875, 0.48829, 0.14128, 0.01168],
+            Window::FlatTop => {
+                &[0.21557895, 0.41663158, 0.277263158, 0.083578947, 0.006947368]
+            }
+        }
+    }
+
+    /**
+     * The coefficients of a periodic window of "n" points.
+     */
+    pub fn coefficients(&self, n: usize) -> Vec<f64> {
+        (0..n)
+            .map(|i| {
+                let x = TAU * i as f64 / n as f64;
+                self.terms()
+                    .iter()
+                    .enumerate()
+                    .map(|(k, a)| {
+                        let sign = if k % 2 == 0 { 1.0 } else { -1.0 };
+                        sign * a * (k as f64 * x).cos()
+                    })
+                    .sum()
+            })
+            .collect()
+    }
+
+    /**
+     * The number of bins either side of a sine's own bin that its main lobe
+     * spreads into.
+     */
+    fn half_width(&self) -> usize {
+        self.terms().len() + 1
+    }
+}
+
+/**
+ * Compute the discrete Fourier transform of the complex sequence "re" + i
+ * "im" in place, using an iterative radix-2 algorithm.  Panics unless both
+ * slices have the same length, which is a power of two.
+ */
+pub fn fft(re: &mut [f64], im: &mut [f64]) {
+    let n = re.len();
+    assert!(n.is_power_of_two() && im.len() == n);
+
+    /*
+     * Put the input in bit-reversed order, then combine pairs of ever larger
+     * sub-transforms.
+     */
+    let shift = usize::BITS - n.trailing_zeros();
+    for i in 1..n {
+        let j = i.reverse_bits().checked_shr(shift).unwrap_or(0);
+        if j > i {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+
+    let mut len = 2;
+    while len <= n {
+        let (ws, wc) = (-TAU / len as f64).sin_cos();
+        for start in (0..n).step_by(len) {
+            let (mut wr, mut wi) = (1.0, 0.0);
+            for k in 0..len / 2 {
+                let a = start + k;
+                let b = a + len / 2;
+                let tr = re[b] * wr - im[b] * wi;
+                let ti = re[b] * wi + im[b] * wr;
+                re[b] = re[a] - tr;
+                im[b] = im[a] - ti;
+                re[a] += tr;
+                im[a] += ti;
+                (wr, wi) = (wr * wc - wi * ws, wr * ws + wi * wc);
+            }
+        }
+        len *= 2;
+    }
+}
+
+/**
+ * Computes magnitude spectra of blocks of a fixed size.  The buffers needed
+ * for the transform are kept between calls.
+ */
+#[derive(Debug, Clone)]
+pub struct Analyzer {
+    rate: u32,
+    window: Window,
+    coefficients: Vec<f64>,
+    scale: f64,
+    re: Vec<f64>,
+    im: Vec<f64>,
+}
+
+impl Analyzer {
+    /**
+     * Fails with EINVAL unless "size" is a power of two of at least 4.
+     */
+    pub fn new(
+        size: usize,
+        rate: u32,
+        window: Window,
+    ) -> std::io::Result<Self> {
+        if size < 4 || !size.is_power_of_two() {
+            return Err(std::io::Error::from_raw_os_error(libc::EINVAL));
+        }
+
+        let coefficients = window.coefficients(size);
+
+        /*
+         * Scale so that a full scale sine centred on a bin reads 1.0; half
+         * the energy of a real sine appears in the negative frequencies.
+         */
+        let scale = 2.0 / coefficients.iter().sum::<f64>();
+
+        Ok(Analyzer {
+            rate,
+            window,
+            coefficients,
+            scale,
+            re: vec![0.0; size],
+            im: vec![0.0; size],
+        })
+    }
+
+    pub fn size(&self) -> usize {
+        self.coefficients.len()
+    }
+
+    /**
+     * Analyse the first size() samples; a shorter block is padded with
+     * silence.
+     */
+    pub fn analyze(&mut self, samples: &[f32]) -> Spectrum {
+        let n = self.size();
+
+        self.re.fill(0.0);
+        self.im.fill(0.0);
+        for ((r, s), w) in
+            self.re.iter_mut().zip(samples).zip(&self.coefficients)
+        {
+            *r = f64::from(*s) * w;
+        }
+
+        fft(&mut self.re, &mut self.im);
+
+        let magnitudes = self.re[..=n / 2]
+            .iter()
+            .zip(&self.im)
+            .map(|(r, i)| r.hypot(*i) * self.scale)
+            .collect();
+
+        Spectrum { rate: self.rate, size: n, window: self.window, magnitudes }
+    }
+
+    /**
+     * Record size() frames from "dsp" and analyse one channel of them.  The
+     * device must have been opened for recording, and configured with the
+     * given format and channel count.
+     */
+    pub fn capture(
+        &mut self,
+        dsp: &Dsp,
+        format: SampleFormat,
+        channels: usize,
+        channel: usize,
+    ) -> std::io::Result<Spectrum> {
+        if channel >= channels {
+            return Err(std::io::Error::from_raw_os_error(libc::EINVAL));
+        }
+
+        let frame_size = format.size() * channels;
+        let n = self.size();
+
+        let mut samples = Vec::with_capacity(n);
+        /*
+         * Leave room for a partial frame left over from the previous read.
+         */
+        let mut buf = vec![0u8; (n + 1) * frame_size];
+        let mut have = 0;
+        while samples.len() < n {
+            let want = (n - samples.len()) * frame_size;
+            let got = dsp.record(&mut buf[have..have + want])?;
+            if got == 0 {
+                return Err(std::io::ErrorKind::UnexpectedEof.into());
+            }
+            have += got;
+
+            let whole = have - have % frame_size;
+            samples.extend(
+                buf[..whole]
+                    .chunks_exact(frame_size)
+                    .map(|f| format.decode(&f[channel * format.size()..])),
+            );
+            buf.copy_within(whole..have, 0);
+            have -= whole;
+        }
+
+        Ok(self.analyze(&samples))
+    }
+}
+
+/**
+ * The magnitude of each frequency bin from DC to the Nyquist frequency,
+ * linear and relative to full scale; i.e., a full scale sine centred on a bin
+ * reads 1.0 (0 dBFS).
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Spectrum {
+    pub rate: u32,
+    pub size: usize,
+    pub window: Window,
+    pub magnitudes: Vec<f64>,
+}
+
+impl Spectrum {
+    /**
+     * The width of each bin in Hz.
+     */
+    pub fn bin_hz(&self) -> f64 {
+        f64::from(self.rate) / self.size as f64
+    }
+
+    pub fn freq(&self, bin: usize) -> f64 {
+        bin as f64 * self.bin_hz()
+    }
+
+    pub fn bin(&self, freq: f64) -> usize {
+        ((freq / self.bin_hz()).round().max(0.0) as usize)
+            .min(self.magnitudes.len() - 1)
+    }
+
+    pub fn magnitude_db(&self, bin: usize) -> f64 {
+        20.0 * self.magnitudes[bin].log10()
+    }
+
+    /**
+     * The frequency and amplitude of the strongest component, ignoring DC.
+     * The peak is interpolated between bins, so the frequency is usually much
+     * more accurate than the bin width.  None if the spectrum is silent.
+     */
+    pub fn dominant(&self) -> Option<(f64, f64)> {
+        let m = &self.magnitudes;
+        let lo = self.window.half_width();
+        let k = (lo..m.len() - 1).max_by(|&a, &b| m[a].total_cmp(&m[b]))?;
+        if m[k] <= 0.0 {
+            return None;
+        }
+
+        /*
+         * Fit a parabola through the log magnitudes of the peak bin and its
+         * neighbours.
+         */
+        let db = |i: usize| 20.0 * m[i].max(1e-12).log10();
+        let (a, b, c) = (db(k - 1), db(k), db(k + 1));
+        let denom = a - 2.0 * b + c;
+        let p = if denom == 0.0 { 0.0 } else { 0.5 * (a - c) / denom };
+        let peak = b - 0.25 * (a - c) * p;
+
+        Some(((k as f64 + p) * self.bin_hz(), 10f64.powf(peak / 20.0)))
+    }
+
+    /**
+     * Total harmonic distortion plus noise: the RMS of everything but the
+     * fundamental (and DC), as a fraction of the RMS of the whole signal.
+     * Bins within the main lobe of the window around the fundamental are
+     * taken to be the fundamental.  None if the spectrum is silent.
+     */
+    pub fn thd_n(&self, fundamental: f64) -> Option<f64> {
+        let hw = self.window.half_width();
+        let f = self.bin(fundamental);
+
+        let mut total = 0.0;
+        let mut rest = 0.0;
+        for (k, m) in self.magnitudes.iter().enumerate().skip(hw) {
+            let p = m * m;
+            total += p;
+            if k.abs_diff(f) > hw {
+                rest += p;
+            }
+        }
+
+        (total > 0.0).then(|| (rest / total).sqrt())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ThdConfig {
+    pub stream: StreamConfig,
+    /**
+     * The frequency of the test tone.  It is moved to the centre of the
+     * nearest analysis bin, which minimizes leakage.
+     */
+    pub freq: f64,
+    /**
+     * The level of the test tone in dBFS.
+     */
+    pub level_db: f64,
+    pub fft_size: usize,
+    pub window: Window,
+    /**
+     * How long to play the tone.  Analysis uses the middle of the recording,
+     * so this should comfortably exceed the round-trip latency plus the
+     * length of the FFT.
+     */
+    pub duration: Duration,
+}
+
+impl Default for ThdConfig {
+    fn default() -> Self {
+        ThdConfig {
+            stream: StreamConfig {
+                format: sys::AudioFormats::AFMT_S16_LE,
+                rate: 48000,
+                channels: 2,
+            },
+            freq: 997.0,
+            level_db: -3.0,
+            fft_size: 16384,
+            window: Window::BlackmanHarris,
+            duration: Duration::from_secs(1),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ThdReport {
+    /**
+     * The frequency and level (dBFS) of the tone as it was recorded.
+     */
+    pub freq: f64,
+    pub level_db: f64,
+    /**
+     * THD+N as a ratio; see Spectrum::thd_n().
+     */
+    pub thd_n: f64,
+    pub spectrum: Spectrum,
+}
+
+impl ThdReport {
+    pub fn thd_n_db(&self) -> f64 {
+        20.0 * self.thd_n.log10()
+    }
+
+    pub fn thd_n_percent(&self) -> f64 {
+        100.0 * self.thd_n
+    }
+}
+
+/**
+ * Play a sine on every channel of "output" while recording from "input", and
+ * measure the THD+N of the first recorded channel; e.g., to check an analog
+ * path with a loopback cable.  As with latency::measure(), if "input" is None
+ * then "output" must have been opened with Direction::Duplex.
+ */
+pub fn measure_thd_n(
+    output: &Dsp,
+    input: Option<&Dsp>,
+    config: &ThdConfig,
+) -> std::io::Result<ThdReport> {
+    let einval = || std::io::Error::from_raw_os_error(libc::EINVAL);
+
+    let sc = &config.stream;
+    let fmt = SampleFormat::from_audio_format(sc.format).ok_or_else(einval)?;
+    let channels: usize = sc.channels.try_into().map_err(|_| einval())?;
+    let mut analyzer = Analyzer::new(config.fft_size, sc.rate, config.window)?;
+    let frames = (config.duration.as_secs_f64() * f64::from(sc.rate)) as usize;
+    if channels == 0 || frames < config.fft_size {
+        return Err(einval());
+    }
+
+    if input.is_none() {
+        output.duplex_set()?;
+    }
+    for dsp in std::iter::once(output).chain(input) {
+        dsp.format_set(sc.format)?;
+        dsp.channels_set(sc.channels)?;
+        dsp.speed_set(sc.rate)?;
+    }
+
+    let bin_hz = f64::from(sc.rate) / config.fft_size as f64;
+    let freq = (config.freq / bin_hz).round().max(1.0) * bin_hz;
+
+    let mut play = vec![0u8; frames * fmt.size() * channels];
+    Generator::new(Oscillator::sine(freq, sc.rate), fmt, channels)
+        .level_db(config.level_db)
+        .fill(&mut play);
+
+    let rec = latency::record_while_playing(
+        output,
+        input.unwrap_or(output),
+        fmt,
+        channels,
+        &play,
+    )?;
+
+    let start = (rec.len() - config.fft_size) / 2;
+    let spectrum = analyzer.analyze(&rec[start..]);
+    let (freq, level) = spectrum.dominant().ok_or_else(einval)?;
+    let thd_n = spectrum.thd_n(freq).ok_or_else(einval)?;
+
+    Ok(ThdReport { freq, level_db: 20.0 * level.log10(), thd_n, spectrum })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(freq: f64, amplitude: f64, rate: u32, n: usize) -> Vec<f32> {
+        (0..n)
+            .map(|i| {
+                let t = i as f64 / f64::from(rate);
+                (amplitude * (TAU * freq * t).sin()) as f32
+            })
+            .collect()
+    }
+
+    #[test]
+    fn fft_impulse() {
+        let mut re = [0.0; 8];
+        let mut im = [0.0; 8];
+        re[0] = 1.0;
+        fft(&mut re, &mut im);
+        assert!(re.iter().all(|&r| (r - 1.0).abs() < 1e-12));
+        assert!(im.iter().all(|&i| i.abs() < 1e-12));
+    }
+
+    #[test]
+    fn sine_on_bin() {
+        /*
+         * 1500 Hz is exactly bin 32 of a 1024 point transform at 48 kHz.
+         */
+        for window in [Window::Rectangular, Window::Hann] {
+            let mut a = Analyzer::new(1024, 48000, window).unwrap();
+            let s = a.analyze(&sine(1500.0, 0.5, 48000, 1024));
+
+            assert_eq!(s.bin(1500.0), 32);
+            assert!((s.magnitudes[32] - 0.5).abs() < 1e-4, "{window:?}");
+            for (k, m) in s.magnitudes.iter().enumerate() {
+                if k.abs_diff(32) > 1 {
+                    assert!(*m < 1e-4, "{window:?} bin {k}: {m}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn dominant_between_bins() {
+        let mut a = Analyzer::new(4096, 48000, Window::BlackmanHarris).unwrap();
+        let s = a.analyze(&sine(1000.0, 0.25, 48000, 4096));
+
+        let (freq, amplitude) = s.dominant().unwrap();
+        assert!((freq - 1000.0).abs() < 1.0, "{freq}");
+        assert!((amplitude - 0.25).abs() < 0.025, "{amplitude}");
+        assert!(s.thd_n(1000.0).unwrap() < 1e-3);
+    }
+
+    #[test]
+    fn silence() {
+        let mut a = Analyzer::new(256, 48000, Window::Hann).unwrap();
+        let s = a.analyze(&[]);
+        assert_eq!(s.dominant(), None);
+        assert_eq!(s.thd_n(1000.0), None);
+    }
+
+    #[test]
+    fn bad_size() {
+        assert!(Analyzer::new(2, 48000, Window::Hann).is_err());
+        assert!(Analyzer::new(1000, 48000, Window::Hann).is_err());
+    }
+}
@@ -0,0 +1,480 @@
+use std::{
+    sync::{Arc, Condvar, Mutex},
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use crate::{sys, Dsp};
+
+/*
+ * The longest we will wait for the device to become writable before checking
+ * whether we have been asked to pause or stop.
+ */
+const WAIT_TIMEOUT: Duration = Duration::from_millis(20);
+
+/*
+ * How many fragments of silence we queue for playback before starting a duplex
+ * stream.  This is the margin the data callback has to produce output before
+ * the device runs dry.
+ */
+const DUPLEX_PRIME_FRAGMENTS: usize = 2;
+
+const DUPLEX_TRIGGER: sys::Trigger =
+    sys::Trigger::PCM_ENABLE_INPUT.union(sys::Trigger::PCM_ENABLE_OUTPUT);
+
+/**
+ * The sample format, rate, and channel count a Stream configures on its device
+ * before starting playback.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamConfig {
+    pub format: sys::AudioFormats,
+    pub rate: u32,
+    pub channels: u32,
+}
+
+impl StreamConfig {
+    /**
+     * The size in bytes of one frame; i.e., one sample for every channel.
+     */
+    pub fn frame_size(&self) -> Option<usize> {
+        let ss = self.format.sample_size()?;
+        ss.checked_mul(self.channels.try_into().ok()?)
+    }
+}
+
+#[derive(Debug)]
+pub enum StreamError {
+    /**
+     * The device ran out of audio to play this many times since the last
+     * report.
+     */
+    Underrun(u32),
+    /**
+     * The device had nowhere to put recorded audio this many times since the
+     * last report.
+     */
+    Overrun(u32),
+    /**
+     * An operation on the device failed.  The I/O thread exits after
+     * reporting an error of this kind.
+     */
+    Io(std::io::Error),
+}
+
+impl std::fmt::Display for StreamError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StreamError::Underrun(n) => write!(f, "playback underrun ({n}x)"),
+            StreamError::Overrun(n) => write!(f, "recording overrun ({n}x)"),
+            StreamError::Io(e) => write!(f, "stream I/O error: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for StreamError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            StreamError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/**
+ * I/O errors are passed through unchanged; underruns and overruns become
+ * errors of kind Other.
+ */
+impl From<StreamError> for std::io::Error {
+    fn from(e: StreamError) -> Self {
+        match e {
+            StreamError::Io(e) => e,
+            e => std::io::Error::other(e),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Running,
+    Paused,
+    Stopped,
+}
+
+type Shared = Arc<(Mutex<State>, Condvar)>;
+
+/**
+ * A stream owns a DSP device and a dedicated I/O thread that moves audio
+ * between the device and a data callback, in the format described by the
+ * StreamConfig.  The thread asks for real-time scheduling; if that is not
+ * permitted, it continues at normal priority.
+ *
+ * A playback stream, created with new(), calls the data callback with a
+ * buffer of exactly as many whole frames as will fit in the device play
+ * buffer whenever there is room, and writes the result to the device.
+ *
+ * A duplex stream, created with duplex(), records and plays at the same time.
+ * Each time a block of input frames arrives, the data callback is given that
+ * input along with an output buffer of the same number of frames to fill.
+ * Both directions are started together, and the output is primed with a
+ * fixed amount of silence, so that output written in response to a given
+ * input frame is always played io_offset_frames() frames after that input
+ * frame was captured (plus any latency in the hardware itself).
+ */
+pub struct Stream {
+    shared: Shared,
+    thread: Option<JoinHandle<Dsp>>,
+    offset: Option<usize>,
+}
+
+impl Stream {
+    pub fn new<F>(
+        dsp: Dsp,
+        config: StreamConfig,
+        callback: F,
+    ) -> std::io::Result<Self>
+    where
+        F: FnMut(&mut [u8]) + Send + 'static,
+    {
+        Self::with_error_callback(dsp, config, callback, |_| {})
+    }
+
+    /**
+     * Like new(), but underruns and I/O errors seen by the I/O thread are
+     * passed to the error callback.
+     */
+    pub fn with_error_callback<F, E>(
+        dsp: Dsp,
+        config: StreamConfig,
+        mut callback: F,
+        error_callback: E,
+    ) -> std::io::Result<Self>
+    where
+        F: FnMut(&mut [u8]) + Send + 'static,
+        E: FnMut(StreamError) + Send + 'static,
+    {
+        let frame_size = configure(&dsp, &config)?;
+
+        let mut buf = Vec::new();
+        Self::start(dsp, None, error_callback, move |dsp, shared, ecb| {
+            run_play(dsp, shared, frame_size, &mut buf, &mut callback, ecb)
+        })
+    }
+
+    /**
+     * Create a duplex stream.  The device must have been opened with
+     * Direction::Duplex, and should report PCM_CAP_DUPLEX.
+     */
+    pub fn duplex<F>(
+        dsp: Dsp,
+        config: StreamConfig,
+        callback: F,
+    ) -> std::io::Result<Self>
+    where
+        F: FnMut(&[u8], &mut [u8]) + Send + 'static,
+    {
+        Self::duplex_with_error_callback(dsp, config, callback, |_| {})
+    }
+
+    /**
+     * Like duplex(), but underruns, overruns, and I/O errors seen by the I/O
+     * thread are passed to the error callback.
+     */
+    pub fn duplex_with_error_callback<F, E>(
+        dsp: Dsp,
+        config: StreamConfig,
+        mut callback: F,
+        error_callback: E,
+    ) -> std::io::Result<Self>
+    where
+        F: FnMut(&[u8], &mut [u8]) + Send + 'static,
+        E: FnMut(StreamError) + Send + 'static,
+    {
+        dsp.duplex_set()?;
+        let frame_size = configure(&dsp, &config)?;
+
+        /*
+         * Stop both engines while we prime the play buffer, so that recording
+         * and playback begin at the same instant once we enable them again.
+         */
+        dsp.trigger_set(sys::Trigger::empty())?;
+
+        let space = dsp.space_output()?;
+        let fragment =
+            usize::try_from(space.fragsize).unwrap_or(0) / frame_size;
+        let offset = (DUPLEX_PRIME_FRAGMENTS * fragment).max(1);
+        dsp.play(&vec![0u8; offset * frame_size])?;
+
+        dsp.errors()?;
+        dsp.trigger_set(DUPLEX_TRIGGER)?;
+
+        let mut input = Vec::new();
+        let mut output = Vec::new();
+        Self::start(
+            dsp,
+            Some(offset),
+            error_callback,
+            move |dsp, shared, ecb| {
+                run_duplex(
+                    dsp,
+                    shared,
+                    frame_size,
+                    (&mut input, &mut output),
+                    &mut callback,
+                    ecb,
+                )
+            },
+        )
+    }
+
+    fn start<R, E>(
+        dsp: Dsp,
+        offset: Option<usize>,
+        mut error_callback: E,
+        mut run: R,
+    ) -> std::io::Result<Self>
+    where
+        R: FnMut(&Dsp, &Shared, &mut E) -> std::io::Result<()> + Send + 'static,
+        E: FnMut(StreamError) + Send + 'static,
+    {
+        let shared: Shared =
+            Arc::new((Mutex::new(State::Running), Condvar::new()));
+
+        let thread = {
+            let shared = Arc::clone(&shared);
+            std::thread::Builder::new().name("audio-stream".into()).spawn(
+                move || {
+                    raise_priority();
+
+                    if let Err(e) = run(&dsp, &shared, &mut error_callback) {
+                        *shared.0.lock().unwrap() = State::Stopped;
+                        error_callback(StreamError::Io(e));
+                    }
+                    dsp
+                },
+            )?
+        };
+
+        Ok(Stream { shared, thread: Some(thread), offset })
+    }
+
+    /**
+     * For a duplex stream, the number of frames between the capture of an
+     * input frame and the playback of the output frame produced alongside it,
+     * not counting latency in the hardware.  None for a playback stream.
+     */
+    pub fn io_offset_frames(&self) -> Option<usize> {
+        self.offset
+    }
+
+    /**
+     * Stop calling the data callback and pause the device.  Audio that has
+     * already been queued is retained and will be played after resume().
+     */
+    pub fn pause(&self) {
+        self.set_state(State::Paused);
+    }
+
+    pub fn resume(&self) {
+        self.set_state(State::Running);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        *self.shared.0.lock().unwrap() == State::Paused
+    }
+
+    /**
+     * Stop the I/O thread and halt the device, discarding any queued audio.
+     * The device is returned so that it may be reused.
+     */
+    pub fn stop(mut self) -> std::io::Result<Dsp> {
+        let dsp = self.join();
+        dsp.halt()?;
+        Ok(dsp)
+    }
+
+    fn set_state(&self, state: State) {
+        let (mtx, cv) = &*self.shared;
+        let mut cur = mtx.lock().unwrap();
+        if *cur != State::Stopped {
+            *cur = state;
+            cv.notify_all();
+        }
+    }
+
+    fn join(&mut self) -> Dsp {
+        self.set_state(State::Stopped);
+        let thread = self.thread.take().unwrap();
+        match thread.join() {
+            Ok(dsp) => dsp,
+            Err(p) => std::panic::resume_unwind(p),
+        }
+    }
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        if self.thread.is_some() {
+            let dsp = self.join();
+            dsp.halt().ok();
+        }
+    }
+}
+
+/**
+ * Apply the stream configuration to the device, returning the frame size.
+ */
+pub(crate) fn configure(
+    dsp: &Dsp,
+    config: &StreamConfig,
+) -> std::io::Result<usize> {
+    let frame_size = config
+        .frame_size()
+        .filter(|&sz| sz > 0)
+        .ok_or_else(|| std::io::Error::from_raw_os_error(libc::EINVAL))?;
+
+    dsp.format_set(config.format)?;
+    dsp.channels_set(config.channels)?;
+    dsp.speed_set(config.rate)?;
+
+    /*
+     * Discard any errors accumulated before we started.
+     */
+    dsp.errors()?;
+
+    Ok(frame_size)
+}
+
+/**
+ * Block while the stream is paused, stopping the device engines for the
+ * duration and re-enabling those in "resume" afterwards.  Returns false once
+ * the stream has been stopped.
+ */
+fn wait_running(
+    dsp: &Dsp,
+    shared: &Shared,
+    resume: sys::Trigger,
+) -> std::io::Result<bool> {
+    let (mtx, cv) = &**shared;
+
+    let mut state = mtx.lock().unwrap();
+    if *state == State::Paused {
+        dsp.trigger_set(sys::Trigger::empty())?;
+        while *state == State::Paused {
+            state = cv.wait(state).unwrap();
+        }
+        if *state == State::Running {
+            dsp.trigger_set(resume)?;
+        }
+    }
+
+    Ok(*state == State::Running)
+}
+
+fn run_play<F, E>(
+    dsp: &Dsp,
+    shared: &Shared,
+    frame_size: usize,
+    buf: &mut Vec<u8>,
+    callback: &mut F,
+    error_callback: &mut E,
+) -> std::io::Result<()>
+where
+    F: FnMut(&mut [u8]),
+    E: FnMut(StreamError),
+{
+    while wait_running(dsp, shared, sys::Trigger::PCM_ENABLE_OUTPUT)? {
+        if !dsp.wait_writable(Some(WAIT_TIMEOUT))? {
+            continue;
+        }
+
+        let space = dsp.space_output()?;
+        let frames = usize::try_from(space.bytes).unwrap_or(0) / frame_size;
+        if frames > 0 {
+            buf.clear();
+            buf.resize(frames * frame_size, 0);
+            callback(buf);
+            dsp.play(buf)?;
+        }
+
+        report_errors(dsp, error_callback)?;
+    }
+
+    Ok(())
+}
+
+fn run_duplex<F, E>(
+    dsp: &Dsp,
+    shared: &Shared,
+    frame_size: usize,
+    (input, output): (&mut Vec<u8>, &mut Vec<u8>),
+    callback: &mut F,
+    error_callback: &mut E,
+) -> std::io::Result<()>
+where
+    F: FnMut(&[u8], &mut [u8]),
+    E: FnMut(StreamError),
+{
+    while wait_running(dsp, shared, DUPLEX_TRIGGER)? {
+        if !dsp.wait_readable(Some(WAIT_TIMEOUT))? {
+            continue;
+        }
+
+        /*
+         * Read only whole frames, and play exactly as many frames as we
+         * read, so that the distance between input and output established
+         * when the stream was primed never changes.
+         */
+        let space = dsp.space_input()?;
+        let frames = usize::try_from(space.bytes).unwrap_or(0) / frame_size;
+        if frames > 0 {
+            input.resize(frames * frame_size, 0);
+            let n = dsp.record(input)?;
+            input.truncate(n - n % frame_size);
+
+            output.clear();
+            output.resize(input.len(), 0);
+            callback(input, output);
+            dsp.play(output)?;
+        }
+
+        report_errors(dsp, error_callback)?;
+    }
+
+    Ok(())
+}
+
+/**
+ * The driver clears the error counters each time they are read, so each
+ * report covers only the underruns and overruns since the previous one.
+ */
+fn report_errors<E>(dsp: &Dsp, error_callback: &mut E) -> std::io::Result<()>
+where
+    E: FnMut(StreamError),
+{
+    let ei = dsp.errors()?;
+    if ei.play_underruns > 0 {
+        error_callback(StreamError::Underrun(ei.play_underruns));
+    }
+    if ei.rec_overruns > 0 {
+        error_callback(StreamError::Overrun(ei.rec_overruns));
+    }
+    Ok(())
+}
+
+/**
+ * Ask for the lowest real-time priority, which is still above every
+ * time-sharing thread.  This usually requires privileges, so failure is not
+ * an error.
+ */
+fn raise_priority() {
+    unsafe {
+        let mut param: libc::sched_param = std::mem::zeroed();
+        param.sched_priority = libc::sched_get_priority_min(libc::SCHED_FIFO);
+        libc::pthread_setschedparam(
+            libc::pthread_self(),
+            libc::SCHED_FIFO,
+            &param,
+        );
+    }
+}
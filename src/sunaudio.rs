@@ -0,0 +1,523 @@
+/*
+ * The Sun audio(4I) interface: /dev/audio for playing and recording, and
+ * /dev/audioctl for inspecting and changing device settings without
+ * interfering with whoever has the data device open.
+ */
+
+use std::{
+    fs::File,
+    os::fd::{AsFd, AsRawFd, BorrowedFd, RawFd},
+    path::Path,
+};
+
+use libc::c_void;
+
+use crate::{c_chars_to_string, dsp::AudioDev, Direction};
+
+pub mod sys;
+
+use sys::{HwFeatures, InputPorts, OutputPorts, SwFeatures};
+
+const DEFAULT_AUDIO: &str = "/dev/audio";
+
+/**
+ * An open Sun audio device.  Either a data device, which may be used to play
+ * or record audio as well as to change settings, or a control device, which
+ * may only be used to change settings.
+ */
+#[derive(Debug)]
+pub struct SunAudio {
+    f: File,
+}
+
+/**
+ * How audio samples are encoded on the device.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Encoding {
+    None,
+    Ulaw,
+    Alaw,
+    /**
+     * Signed linear PCM, in native byte order.
+     */
+    Linear,
+    /**
+     * Unsigned 8-bit linear PCM.
+     */
+    Linear8,
+    Dvi,
+    Other(u32),
+}
+
+impl Encoding {
+    fn from_raw(raw: libc::c_uint) -> Self {
+        match raw {
+            sys::AUDIO_ENCODING_NONE => Encoding::None,
+            sys::AUDIO_ENCODING_ULAW => Encoding::Ulaw,
+            sys::AUDIO_ENCODING_ALAW => Encoding::Alaw,
+            sys::AUDIO_ENCODING_LINEAR => Encoding::Linear,
+            sys::AUDIO_ENCODING_LINEAR8 => Encoding::Linear8,
+            sys::AUDIO_ENCODING_DVI => Encoding::Dvi,
+            n => Encoding::Other(n),
+        }
+    }
+
+    fn raw(&self) -> libc::c_uint {
+        match self {
+            Encoding::None => sys::AUDIO_ENCODING_NONE,
+            Encoding::Ulaw => sys::AUDIO_ENCODING_ULAW,
+            Encoding::Alaw => sys::AUDIO_ENCODING_ALAW,
+            Encoding::Linear => sys::AUDIO_ENCODING_LINEAR,
+            Encoding::Linear8 => sys::AUDIO_ENCODING_LINEAR8,
+            Encoding::Dvi => sys::AUDIO_ENCODING_DVI,
+            Encoding::Other(n) => *n,
+        }
+    }
+}
+
+/**
+ * The state of one direction (play or record) of a device.  The port type is
+ * OutputPorts for the play side and InputPorts for the record side.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PrInfo<P> {
+    pub sample_rate: u32,
+    pub channels: u32,
+    pub precision: u32,
+    pub encoding: Encoding,
+    /**
+     * From sys::AUDIO_MIN_GAIN to sys::AUDIO_MAX_GAIN.
+     */
+    pub gain: u32,
+    pub port: P,
+    pub avail_ports: P,
+    pub mod_ports: P,
+    pub buffer_size: u32,
+    /**
+     * The number of samples played or recorded so far.
+     */
+    pub samples: u32,
+    /**
+     * The number of end-of-file marks (zero-length writes) that have been
+     * reached during playback.
+     */
+    pub eof: u32,
+    pub pause: bool,
+    /**
+     * Whether an underrun or overrun has occurred since the flag was last
+     * cleared.
+     */
+    pub error: bool,
+    pub waiting: bool,
+    /**
+     * From sys::AUDIO_LEFT_BALANCE to sys::AUDIO_RIGHT_BALANCE.
+     */
+    pub balance: u8,
+    pub minordev: u16,
+    pub open: bool,
+    pub active: bool,
+}
+
+impl<P> PrInfo<P> {
+    fn from_raw(raw: &sys::audio_prinfo_t, port: fn(u32) -> P) -> Self {
+        PrInfo {
+            sample_rate: raw.sample_rate,
+            channels: raw.channels,
+            precision: raw.precision,
+            encoding: Encoding::from_raw(raw.encoding),
+            gain: raw.gain,
+            port: port(raw.port),
+            avail_ports: port(raw.avail_ports),
+            mod_ports: port(raw.mod_ports),
+            buffer_size: raw.buffer_size,
+            samples: raw.samples,
+            eof: raw.eof,
+            pause: raw.pause != 0,
+            error: raw.error != 0,
+            waiting: raw.waiting != 0,
+            balance: raw.balance,
+            minordev: raw.minordev,
+            open: raw.open != 0,
+            active: raw.active != 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SunAudioInfo {
+    pub play: PrInfo<OutputPorts>,
+    pub record: PrInfo<InputPorts>,
+    pub monitor_gain: u32,
+    pub output_muted: bool,
+    pub hw_features: HwFeatures,
+    pub sw_features: SwFeatures,
+    pub sw_features_enabled: SwFeatures,
+}
+
+impl SunAudioInfo {
+    fn from_raw(raw: &sys::audio_info_t) -> Self {
+        SunAudioInfo {
+            play: PrInfo::from_raw(&raw.play, OutputPorts::from_bits_retain),
+            record: PrInfo::from_raw(&raw.record, InputPorts::from_bits_retain),
+            monitor_gain: raw.monitor_gain,
+            output_muted: raw.output_muted != 0,
+            hw_features: HwFeatures::from_bits_retain(raw.hw_features),
+            sw_features: SwFeatures::from_bits_retain(raw.sw_features),
+            sw_features_enabled: SwFeatures::from_bits_retain(
+                raw.sw_features_enabled,
+            ),
+        }
+    }
+}
+
+/**
+ * Changes to make to one direction of a device with SunAudio::update().
+ * Settings left as None are not changed.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrUpdate<P> {
+    pub sample_rate: Option<u32>,
+    pub channels: Option<u32>,
+    pub precision: Option<u32>,
+    pub encoding: Option<Encoding>,
+    pub gain: Option<u32>,
+    pub port: Option<P>,
+    pub buffer_size: Option<u32>,
+    /**
+     * Reset the sample counter; usually to zero.
+     */
+    pub samples: Option<u32>,
+    /**
+     * Reset the end-of-file counter; usually to zero.
+     */
+    pub eof: Option<u32>,
+    pub pause: Option<bool>,
+    pub error: Option<bool>,
+    pub balance: Option<u8>,
+}
+
+/*
+ * Derived Default would needlessly require the port type to implement Default.
+ */
+impl<P> Default for PrUpdate<P> {
+    fn default() -> Self {
+        PrUpdate {
+            sample_rate: None,
+            channels: None,
+            precision: None,
+            encoding: None,
+            gain: None,
+            port: None,
+            buffer_size: None,
+            samples: None,
+            eof: None,
+            pause: None,
+            error: None,
+            balance: None,
+        }
+    }
+}
+
+impl<P: bitflags::Flags<Bits = u32>> PrUpdate<P> {
+    fn apply(&self, raw: &mut sys::audio_prinfo_t) -> std::io::Result<()> {
+        /*
+         * All ones means "leave this alone", so we must not let a caller
+         * pass that value through by accident.
+         */
+        fn check(v: u32) -> std::io::Result<u32> {
+            if v == u32::MAX {
+                return Err(std::io::Error::from_raw_os_error(libc::EINVAL));
+            }
+            Ok(v)
+        }
+
+        if let Some(v) = self.sample_rate {
+            raw.sample_rate = check(v)?;
+        }
+        if let Some(v) = self.channels {
+            raw.channels = check(v)?;
+        }
+        if let Some(v) = self.precision {
+            raw.precision = check(v)?;
+        }
+        if let Some(v) = self.encoding {
+            raw.encoding = check(v.raw())?;
+        }
+        if let Some(v) = self.gain {
+            if v > sys::AUDIO_MAX_GAIN {
+                return Err(std::io::Error::from_raw_os_error(libc::EINVAL));
+            }
+            raw.gain = v;
+        }
+        if let Some(v) = &self.port {
+            raw.port = check(v.bits())?;
+        }
+        if let Some(v) = self.buffer_size {
+            raw.buffer_size = check(v)?;
+        }
+        if let Some(v) = self.samples {
+            raw.samples = check(v)?;
+        }
+        if let Some(v) = self.eof {
+            raw.eof = check(v)?;
+        }
+        if let Some(v) = self.pause {
+            raw.pause = v.into();
+        }
+        if let Some(v) = self.error {
+            raw.error = v.into();
+        }
+        if let Some(v) = self.balance {
+            if v > sys::AUDIO_RIGHT_BALANCE {
+                return Err(std::io::Error::from_raw_os_error(libc::EINVAL));
+            }
+            raw.balance = v;
+        }
+
+        Ok(())
+    }
+}
+
+/**
+ * Changes to make to a device with SunAudio::update().  Settings left as None
+ * are not changed.
+ */
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SunAudioUpdate {
+    pub play: PrUpdate<OutputPorts>,
+    pub record: PrUpdate<InputPorts>,
+    pub monitor_gain: Option<u32>,
+    pub output_muted: Option<bool>,
+}
+
+/**
+ * The identity of the hardware behind a device, from AUDIO_GETDEV.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AudioDevice {
+    pub name: String,
+    pub version: String,
+    pub config: String,
+}
+
+impl SunAudio {
+    /**
+     * Open a data device (e.g., "/dev/audio") to play audio, record audio, or
+     * both.
+     */
+    pub fn open<P: AsRef<Path>>(
+        path: P,
+        direction: Direction,
+    ) -> std::io::Result<Self> {
+        let f = std::fs::OpenOptions::new()
+            .read(direction != Direction::Play)
+            .write(direction != Direction::Record)
+            .open(path.as_ref())?;
+
+        Ok(SunAudio { f })
+    }
+
+    /**
+     * Open a control device (e.g., "/dev/audioctl").  A control device may be
+     * opened while another process is using the data device, and can be used
+     * to change or observe its settings.
+     */
+    pub fn open_ctl<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        let f = std::fs::OpenOptions::new().read(true).open(path.as_ref())?;
+
+        Ok(SunAudio { f })
+    }
+
+    /**
+     * Open the data device named by the AUDIODEV environment variable, or
+     * "/dev/audio" if it is not set.  As for Dsp::open_default(), AUDIODEV
+     * may hold either a path or an audio device number from the mixer.
+     */
+    pub fn open_default(direction: Direction) -> std::io::Result<Self> {
+        Self::open(default_path()?, direction)
+    }
+
+    /**
+     * Open the control device that goes with the default data device; by
+     * convention, its path is that of the data device with "ctl" appended.
+     */
+    pub fn open_default_ctl() -> std::io::Result<Self> {
+        Self::open_ctl(format!("{}ctl", default_path()?))
+    }
+
+    pub fn info(&self) -> std::io::Result<SunAudioInfo> {
+        let raw: sys::audio_info_t =
+            crate::basic_ioctl(&self.f, sys::AUDIO_GETINFO)?;
+        Ok(SunAudioInfo::from_raw(&raw))
+    }
+
+    /**
+     * Apply the requested changes and return the resulting state of the
+     * device.  The device may not honour every setting exactly (e.g., it may
+     * round the gain), so callers should check the returned values.
+     */
+    pub fn update(
+        &self,
+        update: &SunAudioUpdate,
+    ) -> std::io::Result<SunAudioInfo> {
+        let mut raw = sys::audio_info_t::init();
+        update.play.apply(&mut raw.play)?;
+        update.record.apply(&mut raw.record)?;
+        if let Some(v) = update.monitor_gain {
+            if v > sys::AUDIO_MAX_GAIN {
+                return Err(std::io::Error::from_raw_os_error(libc::EINVAL));
+            }
+            raw.monitor_gain = v;
+        }
+        if let Some(v) = update.output_muted {
+            raw.output_muted = v.into();
+        }
+
+        let raw = crate::basic_ioctl_inout(&self.f, sys::AUDIO_SETINFO, raw)?;
+        Ok(SunAudioInfo::from_raw(&raw))
+    }
+
+    /**
+     * Wait until all audio written so far has been played.
+     */
+    pub fn drain(&self) -> std::io::Result<()> {
+        crate::basic_ioctl_noarg(&self.f, sys::AUDIO_DRAIN)
+    }
+
+    pub fn device(&self) -> std::io::Result<AudioDevice> {
+        let raw: sys::audio_device_t =
+            crate::basic_ioctl(&self.f, sys::AUDIO_GETDEV)?;
+
+        Ok(AudioDevice {
+            name: c_chars_to_string(&raw.name),
+            version: c_chars_to_string(&raw.version),
+            config: c_chars_to_string(&raw.config),
+        })
+    }
+
+    pub fn play_port_set(
+        &self,
+        port: OutputPorts,
+    ) -> std::io::Result<SunAudioInfo> {
+        self.update(&SunAudioUpdate {
+            play: PrUpdate { port: Some(port), ..Default::default() },
+            ..Default::default()
+        })
+    }
+
+    pub fn record_port_set(
+        &self,
+        port: InputPorts,
+    ) -> std::io::Result<SunAudioInfo> {
+        self.update(&SunAudioUpdate {
+            record: PrUpdate { port: Some(port), ..Default::default() },
+            ..Default::default()
+        })
+    }
+
+    /**
+     * Pause playback, recording, or both.  Audio already buffered is kept,
+     * and resumes from where it left off.
+     */
+    pub fn pause(&self, direction: Direction) -> std::io::Result<()> {
+        self.pause_set(direction, true)
+    }
+
+    pub fn resume(&self, direction: Direction) -> std::io::Result<()> {
+        self.pause_set(direction, false)
+    }
+
+    fn pause_set(
+        &self,
+        direction: Direction,
+        pause: bool,
+    ) -> std::io::Result<()> {
+        let mut update = SunAudioUpdate::default();
+        if direction != Direction::Record {
+            update.play.pause = Some(pause);
+        }
+        if direction != Direction::Play {
+            update.record.pause = Some(pause);
+        }
+        self.update(&update)?;
+        Ok(())
+    }
+
+    /**
+     * Write an end-of-file mark into the play stream.  When playback reaches
+     * the mark, the play "eof" counter is incremented; this lets a program
+     * find out how far through a sequence of sounds the device has got.
+     */
+    pub fn play_eof(&self) -> std::io::Result<()> {
+        self.play_some(&[])?;
+        Ok(())
+    }
+
+    /**
+     * Write as much of the buffer as the device will accept, returning the
+     * number of bytes written.
+     */
+    pub fn play_some(&self, buf: &[u8]) -> std::io::Result<usize> {
+        let fd = self.f.as_raw_fd();
+
+        let wsz = unsafe {
+            libc::write(fd, buf.as_ptr() as *const c_void, buf.len())
+        };
+        if wsz < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        Ok(wsz as usize)
+    }
+
+    /**
+     * Read recorded audio into the buffer, returning the number of bytes read.
+     */
+    pub fn record(&self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let fd = self.f.as_raw_fd();
+
+        let rsz = unsafe {
+            libc::read(fd, buf.as_mut_ptr() as *mut c_void, buf.len())
+        };
+        if rsz < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        Ok(rsz as usize)
+    }
+}
+
+fn default_path() -> std::io::Result<String> {
+    match AudioDev::from_env() {
+        Some(AudioDev::Path(path)) => Ok(path),
+        Some(dev) => {
+            /*
+             * Each device's Sun audio node sits beside its DSP node, with the
+             * same name less the "dsp" suffix; e.g., "/dev/sound/audiohd:0"
+             * and "/dev/sound/audiohd:0dsp".
+             */
+            let dsp = dev.dsp_path()?;
+            dsp.strip_suffix("dsp")
+                .map(str::to_string)
+                .ok_or_else(|| std::io::Error::from_raw_os_error(libc::ENODEV))
+        }
+        None => Ok(DEFAULT_AUDIO.to_string()),
+    }
+}
+
+impl AsFd for SunAudio {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.f.as_fd()
+    }
+}
+
+impl AsRawFd for SunAudio {
+    fn as_raw_fd(&self) -> RawFd {
+        self.f.as_raw_fd()
+    }
+}
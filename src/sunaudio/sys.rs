@@ -0,0 +1,199 @@
+/*
+ * Bindings for the Sun audio(4I) interface, from <sys/audioio.h>.  This
+ * interface predates OSS and is still used by a good deal of software on
+ * illumos.  The ioctl encoding differs from the one OSS uses; see
+ * <sys/ioccom.h>.
+ */
+
+#![allow(non_camel_case_types)]
+
+use bitflags::bitflags;
+use libc::{c_char, c_int, c_uchar, c_uint, c_ushort};
+
+pub const IOCPARM_MASK: c_uint = 0xff;
+
+pub const IOC_VOID: c_uint = 0x20000000;
+pub const IOC_OUT: c_uint = 0x40000000;
+pub const IOC_IN: c_uint = 0x80000000;
+
+pub const IOC_INOUT: c_uint = IOC_IN | IOC_OUT;
+
+macro_rules! IOC_SZ {
+    ($t:ty) => {
+        (((std::mem::size_of::<$t>() as c_uint) & IOCPARM_MASK) << 16)
+    };
+}
+
+macro_rules! _IO {
+    ($x:literal, $y:literal) => {
+        ((IOC_VOID | (($x as c_uint) << 8) | $y) as c_int)
+    };
+}
+
+macro_rules! _IOR {
+    ($x:literal, $y:literal, $t:ty) => {
+        ((IOC_OUT | IOC_SZ!($t) | (($x as c_uint) << 8) | $y) as c_int)
+    };
+}
+
+macro_rules! _IOWR {
+    ($x:literal, $y:literal, $t:ty) => {
+        ((IOC_INOUT | IOC_SZ!($t) | (($x as c_uint) << 8) | $y) as c_int)
+    };
+}
+
+pub const AUDIO_GETINFO: c_int = _IOR!('A', 1, audio_info_t);
+pub const AUDIO_SETINFO: c_int = _IOWR!('A', 2, audio_info_t);
+pub const AUDIO_DRAIN: c_int = _IO!('A', 3);
+pub const AUDIO_GETDEV: c_int = _IOR!('A', 4, audio_device_t);
+
+pub const AUDIO_ENCODING_NONE: c_uint = 0;
+pub const AUDIO_ENCODING_ULAW: c_uint = 1;
+pub const AUDIO_ENCODING_ALAW: c_uint = 2;
+pub const AUDIO_ENCODING_LINEAR: c_uint = 3;
+pub const AUDIO_ENCODING_DVI: c_uint = 104;
+pub const AUDIO_ENCODING_LINEAR8: c_uint = 105;
+
+pub const AUDIO_MIN_GAIN: c_uint = 0;
+pub const AUDIO_MAX_GAIN: c_uint = 255;
+
+pub const AUDIO_LEFT_BALANCE: c_uchar = 0;
+pub const AUDIO_MID_BALANCE: c_uchar = 32;
+pub const AUDIO_RIGHT_BALANCE: c_uchar = 64;
+
+bitflags! {
+    #[repr(transparent)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct OutputPorts: c_uint {
+        const AUDIO_SPEAKER = 0x01;
+        const AUDIO_HEADPHONE = 0x02;
+        const AUDIO_LINE_OUT = 0x04;
+        const AUDIO_SPDIF_OUT = 0x08;
+        const AUDIO_AUX1_OUT = 0x10;
+        const AUDIO_AUX2_OUT = 0x20;
+
+        /*
+         * Other bits may have been set by the OS.
+         */
+        const _ = !0;
+    }
+}
+
+bitflags! {
+    #[repr(transparent)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct InputPorts: c_uint {
+        const AUDIO_MICROPHONE = 0x01;
+        const AUDIO_LINE_IN = 0x02;
+        const AUDIO_CD = 0x04;
+        const AUDIO_SPDIF_IN = 0x08;
+        const AUDIO_AUX1_IN = 0x10;
+        const AUDIO_AUX2_IN = 0x20;
+        const AUDIO_CODEC_LOOPB_IN = 0x40;
+        const AUDIO_SUNVTS = 0x80;
+
+        /*
+         * Other bits may have been set by the OS.
+         */
+        const _ = !0;
+    }
+}
+
+bitflags! {
+    #[repr(transparent)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct HwFeatures: c_uint {
+        const AUDIO_HWFEATURE_DUPLEX = 0x01;
+        const AUDIO_HWFEATURE_MSCODEC = 0x02;
+        const AUDIO_HWFEATURE_IN2OUT = 0x04;
+        const AUDIO_HWFEATURE_PLAY = 0x08;
+        const AUDIO_HWFEATURE_RECORD = 0x10;
+
+        /*
+         * Other bits may have been set by the OS.
+         */
+        const _ = !0;
+    }
+}
+
+bitflags! {
+    #[repr(transparent)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct SwFeatures: c_uint {
+        const AUDIO_SWFEATURE_MIXER = 0x01;
+
+        /*
+         * Other bits may have been set by the OS.
+         */
+        const _ = !0;
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct audio_prinfo_t {
+    pub sample_rate: c_uint,
+    pub channels: c_uint,
+    pub precision: c_uint,
+    pub encoding: c_uint,
+    pub gain: c_uint,
+    pub port: c_uint,
+    pub avail_ports: c_uint,
+    pub mod_ports: c_uint,
+    pub _xxx: c_uint,
+    pub buffer_size: c_uint,
+    pub samples: c_uint,
+    pub eof: c_uint,
+    pub pause: c_uchar,
+    pub error: c_uchar,
+    pub waiting: c_uchar,
+    pub balance: c_uchar,
+    pub minordev: c_ushort,
+    pub open: c_uchar,
+    pub active: c_uchar,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct audio_info_t {
+    pub play: audio_prinfo_t,
+    pub record: audio_prinfo_t,
+    pub monitor_gain: c_uint,
+    pub output_muted: c_uchar,
+    pub ref_cnt: c_uchar,
+    pub _xxx: [c_uchar; 2],
+    pub hw_features: c_uint,
+    pub sw_features: c_uint,
+    pub sw_features_enabled: c_uint,
+}
+
+impl audio_info_t {
+    /**
+     * The equivalent of AUDIO_INITINFO(): every field is set to all ones,
+     * which AUDIO_SETINFO takes to mean "leave this setting alone".  Only the
+     * fields to be changed should then be filled in.
+     */
+    pub fn init() -> Self {
+        let mut info = std::mem::MaybeUninit::<Self>::uninit();
+        unsafe {
+            std::ptr::write_bytes(info.as_mut_ptr(), 0xff, 1);
+            info.assume_init()
+        }
+    }
+}
+
+pub const MAX_AUDIO_DEV_LEN: usize = 16;
+
+#[repr(C)]
+pub struct audio_device_t {
+    pub name: [c_char; MAX_AUDIO_DEV_LEN],
+    pub version: [c_char; MAX_AUDIO_DEV_LEN],
+    pub config: [c_char; MAX_AUDIO_DEV_LEN],
+}
+
+/*
+ * Make sure struct sizes match the C definitions.
+ */
+const _: () = assert!(std::mem::size_of::<audio_prinfo_t>() == 0x38);
+const _: () = assert!(std::mem::size_of::<audio_info_t>() == 0x84);
+const _: () = assert!(std::mem::size_of::<audio_device_t>() == 0x30);
@@ -1,7 +1,7 @@
 #![allow(non_camel_case_types)]
 
 use bitflags::bitflags;
-use libc::{c_char, c_int, c_uint};
+use libc::{c_char, c_int, c_short, c_uint};
 
 pub const OSSIOCPARM_MASK: c_int = 0x1fff;
 
@@ -30,6 +30,12 @@ macro_rules! __OSSIOR {
     };
 }
 
+macro_rules! __OSSIOW {
+    ($x:literal, $y:literal, $t:ty) => {
+        (OSSIOC_IN | OSSIOC_SZ!($t) | (($x as c_int) << 8) | $y)
+    };
+}
+
 macro_rules! __OSSIOWR {
     ($x:literal, $y:literal, $t:ty) => {
         (OSSIOC_INOUT | OSSIOC_SZ!($t) | (($x as c_int) << 8) | $y)
@@ -49,12 +55,26 @@ pub const SNDCTL_DSP_CHANNELS: c_int = __OSSIOWR!('P', 6, c_int);
 pub const SNDCTL_DSP_GETFMTS: c_int = __OSSIOR!('P', 11, c_int);
 pub const SNDCTL_DSP_GETOSPACE: c_int = __OSSIOR!('P', 12, audio_buf_info);
 pub const SNDCTL_DSP_GETISPACE: c_int = __OSSIOR!('P', 13, audio_buf_info);
+pub const SNDCTL_DSP_GETTRIGGER: c_int = __OSSIOR!('P', 16, c_int);
+pub const SNDCTL_DSP_SETTRIGGER: c_int = __OSSIOW!('P', 16, c_int);
+pub const SNDCTL_DSP_SETDUPLEX: c_int = __OSSIO!('P', 22);
 pub const SNDCTL_DSP_GETODELAY: c_int = __OSSIOR!('P', 23, c_int);
 pub const SNDCTL_DSP_GETPLAYVOL: c_int = __OSSIOR!('P', 24, c_int);
 pub const SNDCTL_DSP_SETPLAYVOL: c_int = __OSSIOWR!('P', 24, c_int);
 pub const SNDCTL_DSP_GETERROR: c_int = __OSSIOR!('P', 25, audio_errinfo);
+pub const SNDCTL_DSP_GET_RECSRC_NAMES: c_int =
+    __OSSIOR!('P', 37, oss_mixer_enuminfo);
+pub const SNDCTL_DSP_GET_RECSRC: c_int = __OSSIOR!('P', 38, c_int);
+pub const SNDCTL_DSP_SET_RECSRC: c_int = __OSSIOWR!('P', 38, c_int);
+pub const SNDCTL_DSP_GET_PLAYTGT_NAMES: c_int =
+    __OSSIOR!('P', 39, oss_mixer_enuminfo);
+pub const SNDCTL_DSP_GET_PLAYTGT: c_int = __OSSIOR!('P', 40, c_int);
+pub const SNDCTL_DSP_SET_PLAYTGT: c_int = __OSSIOWR!('P', 40, c_int);
+pub const SNDCTL_DSP_GETRECVOL: c_int = __OSSIOR!('P', 41, c_int);
+pub const SNDCTL_DSP_SETRECVOL: c_int = __OSSIOWR!('P', 41, c_int);
 pub const SNDCTL_DSP_HALT_INPUT: c_int = __OSSIO!('P', 33);
 pub const SNDCTL_DSP_HALT_OUTPUT: c_int = __OSSIO!('P', 34);
+pub const SNDCTL_DSP_LOW_WATER: c_int = __OSSIOW!('P', 34, c_int);
 
 pub const OSS_GETVERSION: c_int = __OSSIOR!('M', 118, c_int);
 
@@ -109,6 +129,20 @@ bitflags! {
     }
 }
 
+bitflags! {
+    #[repr(transparent)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct AudioBusy: libc::c_int {
+        const OPEN_READ = 0x00000001;
+        const OPEN_WRITE = 0x00000002;
+
+        /*
+         * Other bits may have been set by the OS.
+         */
+        const _ = !0;
+    }
+}
+
 pub const PCM_CAP_REVISION: c_int = 0x000000ff; /* Revision level (0 to 255) */
 pub const PCM_CAP_CH_MASK: c_int = 0x06000000; /* See DSP_CH_MASK below */
 
@@ -217,6 +251,28 @@ pub struct audio_errinfo {
     pub filler: [c_int; 16],
 }
 
+pub const OSS_ENUM_MAXVALUE: usize = 255;
+pub const OSS_ENUM_STRINGSIZE: usize = 3000;
+
+#[repr(C)]
+pub struct oss_mixer_enuminfo {
+    pub dev: c_int,
+    pub ctrl: c_int,
+    pub nvalues: c_int,
+    pub version: c_int,
+    pub strindex: [c_short; OSS_ENUM_MAXVALUE],
+    pub strings: [c_char; OSS_ENUM_STRINGSIZE],
+}
+
+bitflags! {
+    #[repr(transparent)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Trigger: libc::c_int {
+        const PCM_ENABLE_INPUT = 0x00000001;
+        const PCM_ENABLE_OUTPUT = 0x00000002;
+    }
+}
+
 #[derive(Debug, Default)]
 #[repr(C)]
 pub struct audio_buf_info {
@@ -262,6 +318,43 @@ bitflags! {
     }
 }
 
+impl AudioFormats {
+    /**
+     * The number of bytes occupied by one sample of a single linear PCM
+     * format, or None for compressed formats or a combination of formats.
+     */
+    pub fn sample_size(&self) -> Option<usize> {
+        Some(match *self {
+            Self::AFMT_MU_LAW | Self::AFMT_A_LAW | Self::AFMT_U8 => 1,
+            Self::AFMT_S8 => 1,
+            Self::AFMT_S16_LE | Self::AFMT_S16_BE => 2,
+            Self::AFMT_U16_LE | Self::AFMT_U16_BE => 2,
+            Self::AFMT_S24_PACKED => 3,
+            Self::AFMT_S24_LE | Self::AFMT_S24_BE => 4,
+            Self::AFMT_S32_LE | Self::AFMT_S32_BE | Self::AFMT_FLOAT => 4,
+            _ => return None,
+        })
+    }
+}
+
+/**
+ * Return the bytes of a fixed-size string from a driver structure, up to but
+ * not including the first NUL.  If there is no NUL, the whole array is
+ * returned.
+ */
+pub fn c_chars_bytes(input: &[c_char]) -> &[u8] {
+    /*
+     * Safety: c_char and u8 have the same size and alignment.
+     */
+    let bytes = unsafe {
+        std::slice::from_raw_parts(input.as_ptr() as *const u8, input.len())
+    };
+    match bytes.iter().position(|&b| b == 0) {
+        Some(n) => &bytes[..n],
+        None => bytes,
+    }
+}
+
 /*
  * Make sure struct sizes match the C definitions.
  */
@@ -271,3 +364,4 @@ const _: () = assert!(std::mem::size_of::<oss_card_info>() == 0x498);
 const _: () = assert!(std::mem::size_of::<oss_mixerinfo>() == 0x470);
 const _: () = assert!(std::mem::size_of::<audio_errinfo>() == 0x68);
 const _: () = assert!(std::mem::size_of::<audio_buf_info>() == 0x10);
+const _: () = assert!(std::mem::size_of::<oss_mixer_enuminfo>() == 0xdc8);
@@ -0,0 +1,185 @@
+/**
+ * A hardware volume setting, as used by SNDCTL_DSP_GETPLAYVOL and friends.
+ * Each channel is a percentage from 0 to 100.  The driver packs the left
+ * channel into the low byte and the right channel into the next byte; mono
+ * devices use only the left channel.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "VolumeRepr"))]
+pub struct Volume {
+    left: u8,
+    right: u8,
+}
+
+/*
+ * The serialized form of a Volume, which goes through Volume::new() on the
+ * way in so that out of range levels are rejected.
+ */
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+struct VolumeRepr {
+    left: u8,
+    right: u8,
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<VolumeRepr> for Volume {
+    type Error = String;
+
+    fn try_from(v: VolumeRepr) -> Result<Self, Self::Error> {
+        Volume::new(v.left, v.right).map_err(|_| {
+            format!(
+                "volume {}:{} is out of range (0 to {VOLUME_MAX})",
+                v.left, v.right
+            )
+        })
+    }
+}
+
+pub const VOLUME_MAX: u8 = 100;
+
+impl Volume {
+    /**
+     * Create a volume with separate left and right levels.  Fails with
+     * EINVAL if either level is above 100.
+     */
+    pub fn new(left: u8, right: u8) -> std::io::Result<Self> {
+        if left > VOLUME_MAX || right > VOLUME_MAX {
+            return Err(std::io::Error::from_raw_os_error(libc::EINVAL));
+        }
+
+        Ok(Volume { left, right })
+    }
+
+    /**
+     * Create a volume with the same level on both channels.
+     */
+    pub fn mono(level: u8) -> std::io::Result<Self> {
+        Self::new(level, level)
+    }
+
+    /**
+     * Create a volume from an overall level and a balance from -1.0 (left
+     * only) through 0.0 (centre) to 1.0 (right only).  The louder channel is
+     * set to the level, and the other is attenuated linearly.
+     */
+    pub fn with_balance(level: u8, balance: f64) -> std::io::Result<Self> {
+        if !(-1.0..=1.0).contains(&balance) {
+            return Err(std::io::Error::from_raw_os_error(libc::EINVAL));
+        }
+
+        let l = f64::from(level) * (1.0 - balance).min(1.0);
+        let r = f64::from(level) * (1.0 + balance).min(1.0);
+        Self::new(l.round() as u8, r.round() as u8)
+    }
+
+    /**
+     * Create a volume with the same level on both channels, given as a gain
+     * in decibels relative to full volume.  See db().
+     */
+    pub fn from_db(db: f64) -> std::io::Result<Self> {
+        Self::mono(db_to_percent(db)?)
+    }
+
+    pub fn left(&self) -> u8 {
+        self.left
+    }
+
+    pub fn right(&self) -> u8 {
+        self.right
+    }
+
+    /**
+     * The level of the louder channel.
+     */
+    pub fn level(&self) -> u8 {
+        self.left.max(self.right)
+    }
+
+    /**
+     * The balance between the channels, from -1.0 (left only) to 1.0 (right
+     * only).  A silent volume is reported as centred.
+     */
+    pub fn balance(&self) -> f64 {
+        let level = self.level();
+        if level == 0 {
+            return 0.0;
+        }
+
+        (f64::from(self.right) - f64::from(self.left)) / f64::from(level)
+    }
+
+    /**
+     * The same volume, with the balance changed and the overall level kept.
+     */
+    pub fn balance_set(&self, balance: f64) -> std::io::Result<Self> {
+        Self::with_balance(self.level(), balance)
+    }
+
+    /**
+     * The left and right levels as gains in decibels relative to full volume,
+     * treating the percentage as a linear amplitude scale; a level of 0 is
+     * negative infinity.  How the percentage maps to actual attenuation is up
+     * to the driver, so this is nominal.
+     */
+    pub fn db(&self) -> (f64, f64) {
+        (percent_to_db(self.left), percent_to_db(self.right))
+    }
+
+    pub(crate) fn from_raw(raw: libc::c_int) -> std::io::Result<Self> {
+        Self::new((raw & 0xFF) as u8, ((raw >> 8) & 0xFF) as u8)
+    }
+
+    pub(crate) fn raw(&self) -> libc::c_int {
+        libc::c_int::from(self.left) | (libc::c_int::from(self.right) << 8)
+    }
+}
+
+impl std::fmt::Display for Volume {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.left, self.right)
+    }
+}
+
+pub fn percent_to_db(percent: u8) -> f64 {
+    20.0 * (f64::from(percent) / f64::from(VOLUME_MAX)).log10()
+}
+
+/**
+ * The inverse of percent_to_db(), rounded to the nearest percent.  Fails with
+ * EINVAL if the gain is above 0 dB or is NaN.
+ */
+pub fn db_to_percent(db: f64) -> std::io::Result<u8> {
+    if db.is_nan() || db > 0.0 {
+        return Err(std::io::Error::from_raw_os_error(libc::EINVAL));
+    }
+
+    Ok((f64::from(VOLUME_MAX) * 10f64.powf(db / 20.0)).round() as u8)
+}
+
+#[cfg(all(test, feature = "serde"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serde_round_trip() {
+        let v = Volume::new(75, 40).unwrap();
+        let json = serde_json::to_string(&v).unwrap();
+        assert_eq!(json, r#"{"left":75,"right":40}"#);
+        assert_eq!(serde_json::from_str::<Volume>(&json).unwrap(), v);
+    }
+
+    #[test]
+    fn serde_rejects_out_of_range() {
+        for json in [
+            r#"{"left":255,"right":255}"#,
+            r#"{"left":50,"right":101}"#,
+            r#"{"left":101,"right":0}"#,
+        ] {
+            assert!(serde_json::from_str::<Volume>(json).is_err(), "{json}");
+        }
+        let max = r#"{"left":100,"right":100}"#;
+        assert_eq!(serde_json::from_str::<Volume>(max).unwrap().level(), 100);
+    }
+}